@@ -0,0 +1,85 @@
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Document};
+use mongodb::options::FindOptions;
+use mongodb::Collection;
+use serde::Deserialize;
+use tracing::info;
+use crate::events::AddressCase;
+use crate::models::Transfer;
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+
+#[derive(Deserialize)]
+struct TransferQuery {
+    contract: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    skip: Option<u64>,
+    limit: Option<i64>,
+}
+
+#[derive(Clone)]
+struct ApiState {
+    collection: Collection<Transfer>,
+    address_case: AddressCase,
+}
+
+impl TransferQuery {
+    /// Matches addresses given in any case against addresses stored in `address_case`.
+    fn filter(&self, address_case: AddressCase) -> Document {
+        let mut filter = Document::new();
+
+        for (key, value) in [("contract", &self.contract), ("from", &self.from), ("to", &self.to)] {
+            if let Some(value) = value {
+                filter.insert(key, address_case.apply(value.to_lowercase()));
+            }
+        }
+
+        filter
+    }
+}
+
+async fn transfers(
+    State(ApiState { collection, address_case }): State<ApiState>,
+    Query(query): Query<TransferQuery>,
+) -> Result<Json<Vec<Transfer>>, (StatusCode, String)> {
+    let options = FindOptions::builder()
+        .sort(doc! { "block_number": 1, "log_index": 1 })
+        .skip(query.skip)
+        .limit(query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT))
+        .build();
+
+    let transfers = async {
+        collection
+            .find(query.filter(address_case), options)
+            .await?
+            .try_collect::<Vec<Transfer>>()
+            .await
+    }
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(transfers))
+}
+
+/// Serves indexed transfers on `GET /transfers` until the process exits.
+pub async fn serve(collection: Collection<Transfer>, address_case: AddressCase, port: u16) {
+    let app = Router::new()
+        .route("/transfers", get(transfers))
+        .with_state(ApiState { collection, address_case });
+
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port))
+        .await
+        .unwrap_or_else(|e| panic!("Failed to bind API server to port {}: {}", port, e));
+
+    info!(port, "Serving transfers");
+
+    axum::serve(listener, app)
+        .await
+        .unwrap_or_else(|e| panic!("API server failed: {}", e));
+}
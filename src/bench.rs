@@ -0,0 +1,53 @@
+use std::pin::pin;
+use std::time::{Duration, Instant};
+use futures::StreamExt;
+use tokio::sync::Semaphore;
+use crate::fetch;
+use crate::parser::{LogParser, Records};
+use crate::rpc::Rpc;
+
+/// Transfers parsed by one backfill path over a block range and how long fetching and parsing took.
+pub struct Throughput {
+    pub transfers: usize,
+    pub elapsed: Duration,
+}
+
+impl Throughput {
+    pub fn per_second(&self, count: u64) -> f64 {
+        // Rounded to one decimal, like the blocks per second of progress logs.
+        (count as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON) * 10.0).round() / 10.0
+    }
+}
+
+/// Fetches and parses `from_block..=to_block` the default way, every block with all its receipts, by `workers` workers.
+/// Nothing is written.
+pub async fn block_walk(rpc: &Rpc, parser: &LogParser<'_>, receipt_permits: &Semaphore, from_block: u64, to_block: u64, workers: u64) -> Throughput {
+    let started = Instant::now();
+    let mut records = Records::default();
+
+    let mut blocks = pin!(fetch::parse_blocks(rpc, parser, receipt_permits, from_block, to_block, workers as usize));
+    while let Some((_, block_records)) = blocks.next().await {
+        records.append(block_records);
+    }
+
+    Throughput { transfers: records.transfers.len(), elapsed: started.elapsed() }
+}
+
+/// Fetches and parses `from_block..=to_block` the `--get-logs` way, `chunk_size` blocks per eth_getLogs request.
+pub async fn get_logs(rpc: &Rpc, parser: &LogParser<'_>, from_block: u64, to_block: u64, chunk_size: u64) -> Throughput {
+    let started = Instant::now();
+    let mut records = Records::default();
+
+    let mut current_block = from_block;
+    while current_block <= to_block {
+        let window_end = (current_block + chunk_size - 1).min(to_block);
+
+        for block_logs in fetch::fetch_logs(rpc, parser, current_block, window_end).await {
+            parser.parse_block(&block_logs, &mut records);
+        }
+
+        current_block = window_end + 1;
+    }
+
+    Throughput { transfers: records.transfers.len(), elapsed: started.elapsed() }
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::path::Path;
+use bigdecimal::BigDecimal;
+use bigdecimal::num_bigint::BigInt;
+use serde::{Serialize, Deserialize};
+use web3::ethabi::{Event, ParamType};
+use web3::types::U256;
+use self::ContractType::ERC20;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Contract {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symbol: Option<String>,
+    pub decimals: usize,
+    pub erc: ContractType,
+    pub address: String,
+    /// Block the contract was deployed in, used by `--start-at-deployment`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deploy_block: Option<u64>,
+    /// Implementation behind an EIP-1967 proxy at `address`, when its metadata had to be read from there.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub implementation: Option<String>,
+    /// ABI of a Transfer event that deviates from the standard one, e.g. indexes the value or has extra parameters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transfer_abi: Option<Event>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transfer_filter: Option<TransferFilter>,
+    /// Disabled contracts stay in the watchlist but aren't indexed. Only stored when false.
+    #[serde(default = "default_enabled", skip_serializing_if = "is_enabled")]
+    pub enabled: bool,
+}
+
+/// A watchlist entry as written in the contracts file. Missing metadata is fetched from the chain.
+#[derive(Deserialize)]
+pub struct ContractEntry {
+    pub name: Option<String>,
+    pub symbol: Option<String>,
+    pub decimals: Option<usize>,
+    pub erc: ContractType,
+    pub address: String,
+    pub deploy_block: Option<u64>,
+    /// ABI of the contract's Transfer event when it deviates from the standard one. Its first two address parameters are
+    /// read as sender and recipient and its first uint parameter as the value.
+    pub transfer_abi: Option<Event>,
+    /// Conditions the contract's Transfer logs must meet to be indexed.
+    pub transfer_filter: Option<TransferFilter>,
+    /// Set to false to stop indexing the contract without removing it.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+/// Conditions a Transfer log of a contract must meet to be stored, for tokens that emit Transfer events which aren't
+/// transfers, e.g. rebasing or wrapper tokens.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TransferFilter {
+    /// Exact number of topics, including the event topic. Logs with another number are skipped without a warning.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topics: Option<usize>,
+    /// Skips transfers of a zero value.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub exclude_zero_value: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn is_enabled(enabled: &bool) -> bool {
+    *enabled
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Hash, Debug, Clone)]
+pub enum ContractType {
+    ERC20,
+    ERC721
+}
+
+impl Contract {
+    /// Scales a raw token amount by the contract's decimals without losing precision.
+    pub fn normalize_value(&self, value: U256) -> String {
+        self.normalize_amount(BigInt::parse_bytes(value.to_string().as_bytes(), 10).unwrap_or_default())
+    }
+
+    /// Scales a raw amount that may be negative, e.g. a reconstructed balance, by the contract's decimals.
+    pub fn normalize_amount(&self, raw: BigInt) -> String {
+        BigDecimal::new(raw, self.decimals as i64).to_plain_string()
+    }
+}
+
+fn contract(name: &str, decimals: usize, erc: ContractType, address: &str) -> Contract {
+    Contract {
+        name: name.to_string(),
+        symbol: None,
+        decimals,
+        erc,
+        address: address.to_string(),
+        deploy_block: None,
+        implementation: None,
+        transfer_abi: None,
+        transfer_filter: None,
+        enabled: true,
+    }
+}
+
+pub const RONIN_CHAIN_ID: u64 = 2020;
+
+/// The Ronin tokens indexed when no watchlist is given.
+pub fn default_contracts() -> HashMap<String, Contract> {
+    to_map(vec![
+        contract("WETH", 18, ERC20, "0xc99a6a985ed2cac1ef41640596c5a5f9f4e19ef5"),
+        contract("AXS", 18, ERC20, "0xed4a9f48a62fb6fdcfb45bb00c9f61d1a436e58c"),
+        contract("SLP", 0, ERC20, "0xa8754b9fa15fc18bb59458815510e40a12cd2014"),
+    ])
+}
+
+/// Loads a watchlist from a JSON array of contracts.
+pub fn load_contracts(path: &Path) -> Result<Vec<ContractEntry>, String> {
+    let file = std::fs::File::open(path)
+        .map_err(|e| format!("Failed to open contracts file {}: {}", path.display(), e))?;
+
+    let contracts: Vec<ContractEntry> = serde_json::from_reader(std::io::BufReader::new(file))
+        .map_err(|e| format!("Failed to parse contracts file {}: {}", path.display(), e))?;
+
+    for contract in &contracts {
+        if !is_valid_address(&contract.address) {
+            return Err(format!(
+                "Invalid address {:?}, expected a lowercase 0x-prefixed 40 character hex string",
+                contract.address
+            ));
+        }
+
+        if let Some(event) = &contract.transfer_abi {
+            let addresses = event.inputs.iter().filter(|input| input.kind == ParamType::Address).count();
+
+            if addresses < 2 || !event.inputs.iter().any(|input| matches!(input.kind, ParamType::Uint(_))) {
+                return Err(format!("The transfer_abi of {} needs two address parameters and a uint parameter", contract.address));
+            }
+        }
+
+        if let Some(topics) = contract.transfer_filter.as_ref().and_then(|filter| filter.topics) {
+            if !(1..=4).contains(&topics) {
+                return Err(format!("The transfer_filter of {} requires {} topics, logs have between 1 and 4", contract.address, topics));
+            }
+        }
+    }
+
+    Ok(contracts)
+}
+
+pub fn to_map(contracts: Vec<Contract>) -> HashMap<String, Contract> {
+    contracts
+        .into_iter()
+        .map(|c| (c.address.clone(), c))
+        .collect()
+}
+
+/// Whether `address` is a lowercase 0x-prefixed 40 character hex string.
+pub fn is_valid_address(address: &str) -> bool {
+    match address.strip_prefix("0x") {
+        Some(hex) => hex.len() == 40 && hex.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f')),
+        None => false,
+    }
+}
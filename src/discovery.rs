@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use futures::TryStreamExt;
+use mongodb::bson::{doc, to_document};
+use mongodb::options::{IndexOptions, UpdateOptions};
+use mongodb::{Collection, IndexModel};
+use tracing::{debug, info, warn};
+use web3::types::{BlockNumber, FilterBuilder, H160, H256};
+use crate::contracts::{is_valid_address, to_map, Contract};
+use crate::contracts::ContractType::{ERC20, ERC721};
+use crate::events::ERC_TRANSFER_TOPIC;
+use crate::rpc::Rpc;
+use crate::token::fetch_metadata;
+
+/// Scans Transfer logs chain-wide and classifies every contract that emitted one. Contracts
+/// emitting Transfer with three topics and answering `decimals()` are ERC20, four topics are ERC721.
+pub async fn discover(rpc: &Rpc, from_block: u64, to_block: u64, chunk_size: u64) -> Vec<Contract> {
+    let topic: H256 = ERC_TRANSFER_TOPIC.parse().expect("Invalid transfer topic");
+    let mut emitters: HashMap<H160, usize> = HashMap::new();
+
+    let mut start = from_block;
+    while start <= to_block {
+        let end = (start + chunk_size - 1).min(to_block);
+        let filter = FilterBuilder::default()
+            .from_block(BlockNumber::from(start))
+            .to_block(BlockNumber::from(end))
+            .topics(Some(vec![topic]), None, None, None)
+            .build();
+
+        for log in rpc.call(|eth| eth.logs(filter.clone())).await {
+            emitters.entry(log.address).or_insert(log.topics.len());
+        }
+
+        info!(from_block = start, to_block = end, contracts = emitters.len(), "Scanned transfer logs");
+        start = end + 1;
+    }
+
+    let mut contracts = vec![];
+    for (address, topics) in emitters {
+        let metadata = fetch_metadata(rpc, address).await;
+
+        let erc = match (topics, metadata.decimals) {
+            (3, Some(_)) => ERC20,
+            (4, _) => ERC721,
+            _ => {
+                debug!(address = %format!("{:#x}", address), topics, "Skipping contract that is neither ERC20 nor ERC721");
+                continue;
+            }
+        };
+
+        let address = format!("{:#x}", address);
+        contracts.push(Contract {
+            name: metadata.name.or_else(|| metadata.symbol.clone()).unwrap_or_else(|| address.clone()),
+            symbol: metadata.symbol,
+            decimals: metadata.decimals.unwrap_or_default(),
+            erc,
+            address,
+            deploy_block: None,
+            implementation: metadata.implementation.map(|implementation| format!("{:#x}", implementation)),
+            transfer_abi: None,
+            transfer_filter: None,
+            enabled: true,
+        });
+    }
+
+    contracts
+}
+
+/// Upserts discovered contracts by address.
+pub async fn save_contracts(collection: &Collection<Contract>, contracts: &[Contract]) -> mongodb::error::Result<()> {
+    let index = IndexModel::builder()
+        .keys(doc! { "address": 1 })
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    collection.create_index(index, None).await?;
+
+    // $set keeps fields discovery doesn't know, e.g. a contract disabled by hand stays disabled.
+    for contract in contracts {
+        collection
+            .update_one(doc! { "address": &contract.address }, doc! { "$set": to_document(contract)? }, UpdateOptions::builder().upsert(true).build())
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Loads the contracts stored by `save_contracts`, e.g. by `discover` or by hand, skipping invalid addresses.
+pub async fn load_contracts(collection: &Collection<Contract>) -> mongodb::error::Result<HashMap<String, Contract>> {
+    let contracts: Vec<Contract> = collection.find(None, None).await?.try_collect().await?;
+
+    let contracts = contracts
+        .into_iter()
+        .filter(|contract| {
+            let valid = is_valid_address(&contract.address);
+            if !valid {
+                warn!(address = %contract.address, "Skipping stored contract with an invalid address");
+            }
+            valid
+        })
+        .collect();
+
+    Ok(to_map(contracts))
+}
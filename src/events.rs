@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::path::Path;
+use clap::ValueEnum;
+use web3::ethabi::{Event, EventParam, ParamType, Token};
+use web3::signing::keccak256;
+use web3::types::H256;
+use crate::contracts::ContractType;
+use crate::contracts::ContractType::{ERC20, ERC721};
+
+pub const ERC_TRANSFER_TOPIC: &str =
+    "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+pub const ERC_APPROVAL_TOPIC: &str =
+    "0x8c5be1e5ebec7d5bd14f71427d1e84f3dd0314c0f7b2291e5b200ac8c7c3b925";
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EventKind {
+    Transfer,
+    Approval,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum AddressCase {
+    /// Lowercase hex
+    #[default]
+    Lower,
+    /// EIP-55 mixed case checksum
+    Checksum,
+}
+
+impl AddressCase {
+    /// Formats a lowercase 0x-prefixed address in this case.
+    pub fn apply(&self, address: String) -> String {
+        match self {
+            AddressCase::Lower => address,
+            AddressCase::Checksum => checksum_address(&address),
+        }
+    }
+}
+
+/// Checksums a lowercase 0x-prefixed address as in EIP-55: a letter is uppercased when the matching nibble of the
+/// keccak256 hash of the lowercase hex is 8 or higher.
+pub fn checksum_address(address: &str) -> String {
+    let hex = address.trim_start_matches("0x");
+    let hash = keccak256(hex.as_bytes());
+
+    let checksummed: String = hex
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            let nibble = (hash[i / 2] >> if i % 2 == 0 { 4 } else { 0 }) & 0x0f;
+
+            match nibble >= 8 {
+                true => c.to_ascii_uppercase(),
+                false => c,
+            }
+        })
+        .collect();
+
+    format!("0x{}", checksummed)
+}
+
+/// The ERC20 and ERC721 Transfer events share a topic, but ERC721 indexes the token id
+/// as a third topic instead of carrying the value in the log data.
+pub fn transfer_event(erc: &ContractType) -> Event {
+    let (value_name, value_indexed) = match erc {
+        ERC20 => ("_value", false),
+        ERC721 => ("_tokenId", true),
+    };
+
+    erc_event("Transfer", ["_from", "_to", value_name], value_indexed)
+}
+
+/// Same as the Transfer event, the ERC721 Approval event indexes the token id.
+pub fn approval_event(erc: &ContractType) -> Event {
+    let (names, value_indexed) = match erc {
+        ERC20 => (["_owner", "_spender", "_value"], false),
+        ERC721 => (["_owner", "_approved", "_tokenId"], true),
+    };
+
+    erc_event("Approval", names, value_indexed)
+}
+
+fn erc_event(name: &str, [first, second, value]: [&str; 3], value_indexed: bool) -> Event {
+    Event {
+        name: name.to_string(),
+        inputs: vec![
+            EventParam {
+                name: first.to_string(),
+                kind: ParamType::Address,
+                indexed: true,
+            },
+            EventParam {
+                name: second.to_string(),
+                kind: ParamType::Address,
+                indexed: true,
+            },
+            EventParam {
+                name: value.to_string(),
+                kind: ParamType::Uint(256),
+                indexed: value_indexed,
+            },
+        ],
+        anonymous: false,
+    }
+}
+
+/// Loads the named events from a JSON ABI file, keyed by their topic0 hash.
+pub fn load_abi_events(path: &Path, names: &[String]) -> Result<HashMap<H256, Event>, String> {
+    let file = std::fs::File::open(path)
+        .map_err(|e| format!("Failed to open ABI file {}: {}", path.display(), e))?;
+
+    let abi = web3::ethabi::Contract::load(std::io::BufReader::new(file))
+        .map_err(|e| format!("Failed to parse ABI file {}: {}", path.display(), e))?;
+
+    let mut events = HashMap::new();
+    for name in names {
+        let event = abi.event(name)
+            .map_err(|_| format!("Event {} not found in ABI file {}", name, path.display()))?;
+
+        events.insert(event.signature(), event.clone());
+    }
+
+    Ok(events)
+}
+
+/// Formats any parameter as a string, addresses as lowercase hex and integers in decimal.
+pub fn format_token(token: &Token) -> String {
+    match token {
+        Token::Address(address) => format!("{:#x}", address),
+        Token::Uint(value) | Token::Int(value) => value.to_string(),
+        Token::Bytes(bytes) | Token::FixedBytes(bytes) => format!("0x{}", hex::encode(bytes)),
+        Token::Array(tokens) | Token::FixedArray(tokens) | Token::Tuple(tokens) => {
+            format!("[{}]", tokens.iter().map(format_token).collect::<Vec<String>>().join(","))
+        }
+        token => token.to_string(),
+    }
+}
+
+/// Formats an address parameter as a lowercase 0x-prefixed 40 character hex string.
+pub fn format_address(token: &Token) -> Option<String> {
+    match token {
+        Token::Address(address) => Some(format!("{:#x}", address)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use web3::ethabi::RawLog;
+    use web3::types::{H160, H256, U256};
+
+    fn address_topic(address: &str) -> H256 {
+        H256::from(address.parse::<H160>().unwrap())
+    }
+
+    #[test]
+    fn formats_transfer_addresses() {
+        let from = "0x00000000000000000000000000000000000000a1";
+        let to = "0xa8754b9fa15fc18bb59458815510e40a12cd2014";
+
+        let mut data = [0u8; 32];
+        U256::from(1000).to_big_endian(&mut data);
+
+        let log = transfer_event(&ERC20)
+            .parse_log(RawLog {
+                topics: vec![ERC_TRANSFER_TOPIC.parse().unwrap(), address_topic(from), address_topic(to)],
+                data: data.to_vec(),
+            })
+            .unwrap();
+
+        assert_eq!(format_address(&log.params[0].value).unwrap(), from);
+        assert_eq!(format_address(&log.params[1].value).unwrap(), to);
+        assert_eq!(format_address(&log.params[2].value), None);
+    }
+
+    #[test]
+    fn checksums_addresses_as_in_eip55() {
+        for expected in [
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+            "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+            "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+            "0xD1220A0cf47c7B9Be7A2E6BA89F429762e7b9aDb",
+        ] {
+            assert_eq!(checksum_address(&expected.to_lowercase()), expected);
+        }
+
+        assert_eq!(AddressCase::Lower.apply("0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed".to_string()), "0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed");
+    }
+}
@@ -0,0 +1,137 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use clap::ValueEnum;
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Document};
+use mongodb::options::FindOptions;
+use mongodb::Collection;
+use serde::Serialize;
+use crate::events::AddressCase;
+use crate::models::Transfer;
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExportFormat {
+    /// One column per transfer field
+    Csv,
+    /// One JSON transfer per line, which `import` reads back
+    Ndjson,
+}
+
+/// A transfer as a CSV row. Every row has the same columns, a CSV writer rejects rows of another length, so optional
+/// fields are empty cells instead of missing ones and the raw topics share one cell.
+#[derive(Serialize)]
+struct CsvRow<'a> {
+    id: &'a str,
+    schema_version: u8,
+    chain_id: Option<u64>,
+    contract: &'a str,
+    event: &'a str,
+    from: &'a str,
+    to: &'a str,
+    value: &'a str,
+    value_decimal: &'a str,
+    timestamp: u64,
+    block_number: u64,
+    block_hash: &'a str,
+    transaction_hash: &'a str,
+    tx_from: &'a str,
+    log_index: u64,
+    transfer_kind: &'static str,
+    gas_used: Option<u64>,
+    effective_gas_price: Option<u64>,
+    sibling_log_count: Option<u64>,
+    /// Space separated.
+    raw_topics: Option<String>,
+    raw_data: Option<&'a str>,
+}
+
+impl<'a> From<&'a Transfer> for CsvRow<'a> {
+    fn from(transfer: &'a Transfer) -> CsvRow<'a> {
+        CsvRow {
+            id: &transfer.id,
+            schema_version: transfer.schema_version,
+            chain_id: transfer.chain_id,
+            contract: &transfer.contract,
+            event: &transfer.event,
+            from: &transfer.from,
+            to: &transfer.to,
+            value: &transfer.value,
+            value_decimal: &transfer.value_decimal,
+            timestamp: transfer.timestamp,
+            block_number: transfer.block_number,
+            block_hash: &transfer.block_hash,
+            transaction_hash: &transfer.transaction_hash,
+            tx_from: &transfer.tx_from,
+            log_index: transfer.log_index,
+            transfer_kind: transfer.transfer_kind.as_str(),
+            gas_used: transfer.gas_used,
+            effective_gas_price: transfer.effective_gas_price,
+            sibling_log_count: transfer.sibling_log_count,
+            raw_topics: transfer.raw_topics.as_ref().map(|topics| topics.join(" ")),
+            raw_data: transfer.raw_data.as_deref(),
+        }
+    }
+}
+
+/// Streams the transfers matching the filters to a file in `format`, returning the number of transfers written.
+/// `contract` matches in any case against contracts stored in `address_case`.
+pub async fn export(
+    collection: &Collection<Transfer>,
+    path: &Path,
+    format: ExportFormat,
+    address_case: AddressCase,
+    contract: Option<&str>,
+    from_block: Option<u64>,
+    to_block: Option<u64>,
+) -> Result<u64, Box<dyn Error>> {
+    let mut filter = Document::new();
+    if let Some(contract) = contract {
+        filter.insert("contract", address_case.apply(contract.to_lowercase()));
+    }
+
+    let mut block_range = Document::new();
+    if let Some(from_block) = from_block {
+        block_range.insert("$gte", from_block as i64);
+    }
+    if let Some(to_block) = to_block {
+        block_range.insert("$lte", to_block as i64);
+    }
+    if !block_range.is_empty() {
+        filter.insert("block_number", block_range);
+    }
+
+    let options = FindOptions::builder()
+        .sort(doc! { "block_number": 1, "log_index": 1 })
+        .build();
+
+    let mut cursor = collection.find(filter, options).await?;
+
+    let mut rows = 0;
+    match format {
+        ExportFormat::Csv => {
+            let mut writer = csv::Writer::from_path(path)?;
+
+            while let Some(transfer) = cursor.try_next().await? {
+                writer.serialize(CsvRow::from(&transfer))?;
+                rows += 1;
+            }
+
+            writer.flush()?;
+        }
+        ExportFormat::Ndjson => {
+            let mut writer = BufWriter::new(File::create(path)?);
+
+            while let Some(transfer) = cursor.try_next().await? {
+                serde_json::to_writer(&mut writer, &transfer)?;
+                writer.write_all(b"\n")?;
+                rows += 1;
+            }
+
+            writer.flush()?;
+        }
+    }
+
+    Ok(rows)
+}
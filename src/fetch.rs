@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+use futures::future::join_all;
+use futures::{stream, Stream, StreamExt};
+use tokio::sync::Semaphore;
+use tracing::{debug, error, info_span, warn};
+use web3::types::{Block, BlockId, BlockNumber, FilterBuilder, TransactionId, H256};
+use crate::parser::{BlockLogs, BlockMeta, LogParser, Records, TxLog};
+use crate::rpc::Rpc;
+use crate::{to_string, RPC_RECEIPT_RETRIES};
+
+const MISSING_BLOCK_DELAY: Duration = Duration::from_millis(500);
+
+/// Fetches a block header with its transaction hashes and the receipts of all transactions, keeping the logs the parser
+/// matches. Tokens are often moved inside calls to other contracts, or minted by contract creations, so no transaction is
+/// skipped. Transaction bodies aren't needed, receipts carry the sender. Each receipt request holds a permit of
+/// `receipt_permits`, which is shared by all blocks fetched at once to bound the requests in flight.
+pub async fn fetch_block(rpc: &Rpc, parser: &LogParser<'_>, receipt_permits: &Semaphore, number: u64) -> BlockLogs {
+    let block = header(rpc, number).await;
+
+    // Fetch the receipts of the block concurrently, the parser orders their logs as they appear on chain.
+    let receipts = join_all(block.transactions.iter().map(|hash| async move {
+        let _permit = receipt_permits.acquire().await.expect("Receipt semaphore is never closed");
+
+        match rpc.try_call(RPC_RECEIPT_RETRIES, |eth| eth.transaction_receipt(*hash)).await {
+            // Receipts from before EIP-1559 have no effective gas price, the transaction's gas price is what was paid.
+            Ok(Some(mut receipt)) if parser.records_gas() && receipt.effective_gas_price.is_none() && receipt.logs.iter().any(|log| parser.matches(log)) => {
+                match rpc.try_call(RPC_RECEIPT_RETRIES, |eth| eth.transaction(TransactionId::Hash(*hash))).await {
+                    Ok(tx) => receipt.effective_gas_price = tx.and_then(|tx| tx.gas_price),
+                    Err(e) => warn!(transaction = %to_string(hash), error = %e, "Failed to load the gas price of a transaction"),
+                }
+
+                Some(receipt)
+            }
+            Ok(Some(receipt)) => Some(receipt),
+            Ok(None) => {
+                warn!(transaction = %to_string(hash), "Missing receipt, skipping transaction");
+                None
+            }
+            Err(e) => {
+                error!(transaction = %to_string(hash), error = %e, "Failed to load receipt, skipping transaction");
+                None
+            }
+        }
+    })).await;
+
+    parser.block_logs(&block, receipts.into_iter().flatten().collect())
+}
+
+/// Fetches and parses the blocks of `from_block..=to_block` with up to `workers` blocks in flight. Blocks are yielded in
+/// block order, each as soon as it and all blocks before it are done, so a slow block holds back the output but not
+/// the fetching of the blocks after it.
+pub fn parse_blocks<'a>(
+    rpc: &'a Rpc,
+    parser: &'a LogParser<'a>,
+    receipt_permits: &'a Semaphore,
+    from_block: u64,
+    to_block: u64,
+    workers: usize,
+) -> impl Stream<Item = (BlockLogs, Records)> + 'a {
+    stream::iter(from_block..=to_block)
+        .map(move |number| async move { parse_block(parser, fetch_block(rpc, parser, receipt_permits, number).await) })
+        .buffered(workers)
+}
+
+/// Parses the logs of one block into records of its own, within a span of the block.
+pub fn parse_block(parser: &LogParser<'_>, block_logs: BlockLogs) -> (BlockLogs, Records) {
+    let mut records = Records::default();
+
+    if let Some(block) = &block_logs.block {
+        info_span!("block", number = block.number, hash = %block.hash)
+            .in_scope(|| parser.parse_block(&block_logs, &mut records));
+    }
+
+    (block_logs, records)
+}
+
+/// Fetches the matching logs of `from_block..=to_block` with a single eth_getLogs call, plus the headers of
+/// the blocks that contain any. Returns one entry per block in the range.
+pub async fn fetch_logs(rpc: &Rpc, parser: &LogParser<'_>, from_block: u64, to_block: u64) -> Vec<BlockLogs> {
+    let filter = FilterBuilder::default()
+        .from_block(BlockNumber::from(from_block))
+        .to_block(BlockNumber::from(to_block))
+        .address(parser.addresses())
+        .topics(Some(parser.topics().to_vec()), None, None, None)
+        .build();
+
+    let mut logs_by_block: BTreeMap<u64, Vec<TxLog>> = BTreeMap::new();
+    for log in rpc.call(|eth| eth.logs(filter.clone())).await {
+        let Some(number) = log.block_number else {
+            continue;
+        };
+
+        // Neither the transaction nor its receipt is fetched, so its sender, gas and other logs are unknown.
+        logs_by_block.entry(number.as_u64()).or_default().push(TxLog { tx_from: None, gas_used: None, effective_gas_price: None, sibling_log_count: None, log });
+    }
+
+    let headers = join_all(logs_by_block.keys().map(|number| header(rpc, *number))).await;
+
+    let mut blocks: BTreeMap<u64, BlockMeta> = headers
+        .into_iter()
+        .zip(logs_by_block.keys())
+        .map(|(header, number)| (*number, parser.block_meta(&header)))
+        .collect();
+
+    (from_block..=to_block)
+        .map(|number| {
+            let mut logs = logs_by_block.remove(&number).unwrap_or_default();
+            logs.sort_by_key(|tx_log| (tx_log.log.transaction_index, tx_log.log.log_index));
+
+            BlockLogs {
+                number,
+                block: blocks.remove(&number),
+                logs,
+            }
+        })
+        .collect()
+}
+
+/// Fetches the header of block `number` with its transaction hashes. Near the chain head a node behind a load balancer
+/// may not have the block yet and return nothing, so it is retried until it is there.
+async fn header(rpc: &Rpc, number: u64) -> Block<H256> {
+    loop {
+        match rpc.call(|eth| eth.block(BlockId::Number(BlockNumber::from(number)))).await {
+            Some(block) => return block,
+            None => {
+                debug!(block = number, "Block isn't available yet, retrying");
+                tokio::time::sleep(MISSING_BLOCK_DELAY).await;
+            }
+        }
+    }
+}
+
+/// The first block whose timestamp is at or after unix `timestamp` in seconds, found by a binary search over block headers,
+/// or `None` when the chain head is older.
+pub async fn first_block_at(rpc: &Rpc, timestamp: u64) -> Option<u64> {
+    let head = rpc.call(|eth| eth.block_number()).await.as_u64();
+    let block_timestamp = |number: u64| async move { header(rpc, number).await.timestamp.as_u64() };
+
+    if block_timestamp(head).await < timestamp {
+        return None;
+    }
+
+    let (mut low, mut high) = (0, head);
+    while low < high {
+        let middle = low + (high - low) / 2;
+
+        match block_timestamp(middle).await < timestamp {
+            true => low = middle + 1,
+            false => high = middle,
+        }
+    }
+
+    Some(low)
+}
+
+/// The average time between the last `sample` blocks before `head`, or `None` when the chain is too young to tell.
+pub async fn block_time(rpc: &Rpc, head: u64, sample: u64) -> Option<Duration> {
+    if head < sample || sample == 0 {
+        return None;
+    }
+
+    let timestamp = |number: u64| async move {
+        rpc.call(|eth| eth.block(BlockId::Number(BlockNumber::from(number))))
+            .await
+            .map(|header| header.timestamp.as_u64())
+    };
+
+    let (latest, earliest) = (timestamp(head).await?, timestamp(head - sample).await?);
+
+    Some(Duration::from_secs(latest.saturating_sub(earliest) / sample))
+}
@@ -0,0 +1,48 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::LazyLock;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::Router;
+use crate::metrics::METRICS;
+
+pub static HEALTH: LazyLock<Health> = LazyLock::new(Health::default);
+
+/// Progress flags set by the indexer, `/readyz` fails until both are set.
+#[derive(Default)]
+pub struct Health {
+    pub block_processed: AtomicBool,
+    pub batch_persisted: AtomicBool,
+}
+
+async fn readyz(max_lag: u64) -> (StatusCode, String) {
+    if !HEALTH.block_processed.load(Ordering::SeqCst) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "no block processed yet".to_string());
+    }
+
+    if !HEALTH.batch_persisted.load(Ordering::SeqCst) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "no batch persisted yet".to_string());
+    }
+
+    let lag = (METRICS.chain_head_block.get() - METRICS.current_block.get()).max(0) as u64;
+    if lag > max_lag {
+        return (StatusCode::SERVICE_UNAVAILABLE, format!("{} blocks behind the chain head", lag));
+    }
+
+    (StatusCode::OK, "ready".to_string())
+}
+
+/// Serves `/healthz`, which succeeds while the process is alive, and `/readyz`, which succeeds once a block
+/// was processed and persisted and the indexer is at most `max_lag` blocks behind the chain head.
+pub async fn serve(port: u16, max_lag: u64) {
+    let app = Router::new()
+        .route("/healthz", get(|| async { "ok" }))
+        .route("/readyz", get(move || readyz(max_lag)));
+
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port))
+        .await
+        .unwrap_or_else(|e| panic!("Failed to bind health server to port {}: {}", port, e));
+
+    axum::serve(listener, app)
+        .await
+        .unwrap_or_else(|e| panic!("Health server failed: {}", e));
+}
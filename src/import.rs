@@ -0,0 +1,46 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use crate::models::Transfer;
+use crate::sink::TransferSink;
+
+/// Replays transfers from an NDJSON file written by `export --format ndjson` into `sink`, in batches of `batch_size`,
+/// returning the number of transfers read. Checkpoints are left alone, the file may not cover a contiguous range.
+pub async fn import_ndjson(sink: &mut dyn TransferSink, path: &Path, batch_size: usize) -> Result<u64, Box<dyn Error + Send + Sync>> {
+    let reader = BufReader::new(File::open(path)?);
+
+    let mut imported = 0;
+    let mut batch = Vec::with_capacity(batch_size);
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let transfer: Transfer = serde_json::from_str(&line).map_err(|e| format!("line {}: {}", number + 1, e))?;
+        batch.push(transfer);
+
+        if batch.len() >= batch_size {
+            imported += write(sink, &mut batch).await?;
+        }
+    }
+
+    imported += write(sink, &mut batch).await?;
+
+    Ok(imported)
+}
+
+async fn write(sink: &mut dyn TransferSink, batch: &mut Vec<Transfer>) -> Result<u64, Box<dyn Error + Send + Sync>> {
+    if batch.is_empty() {
+        return Ok(0);
+    }
+
+    sink.insert_batch(batch).await?;
+    sink.flush().await?;
+
+    let written = batch.len() as u64;
+    batch.clear();
+
+    Ok(written)
+}
@@ -1,244 +1,1432 @@
-use std::collections::HashMap;
-use thousands::Separable;
-use web3::ethabi::{Event, EventParam, ParamType, RawLog};
-use web3::types::{BlockId, BlockNumber, Log};
-use web3::Web3;
-use serde::{Serialize, Deserialize};
-use mongodb::{Client};
-use mongodb::bson::Document;
-use mongodb::options::IndexOptions;
-use crate::ContractType::ERC20;
-
-const ERC_TRANSFER_TOPIC: &str =
-    "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+mod api;
+mod bench;
+mod contracts;
+mod discovery;
+mod events;
+mod health;
+mod export;
+mod import;
+mod metrics;
+mod fetch;
+mod models;
+mod parser;
+mod progress;
+mod reparse;
+mod rpc;
+mod shard;
+mod status;
+mod sink;
+mod spool;
+mod token;
+mod writer;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use clap::{ArgGroup, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap::error::ErrorKind;
+use futures::{stream, StreamExt};
+use mongodb::Client;
+use mongodb::options::{Acknowledgment, ClientOptions, WriteConcern};
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::EnvFilter;
+use crate::contracts::{Contract, ContractType};
+use crate::events::{AddressCase, EventKind};
+use crate::export::ExportFormat;
+use crate::health::HEALTH;
+use crate::metrics::METRICS;
+use crate::models::{dedup_transfers, BlockStats, TimestampUnit};
+use crate::parser::{LogParser, Records};
+use crate::progress::BlockRate;
+use crate::rpc::Rpc;
+use crate::shard::Shard;
+use crate::writer::{Batch, Writer};
+use crate::sink::{suffixed_collection_name, Backend, DryRunSink, KafkaSink, MongoCollections, MongoSink, ParquetPartition, ParquetSink, PostgresSink, SqliteSink, TransferSink};
+
+const RPC_URL: &str = "ws://127.0.0.1:8546";
+const RPC_MAX_RETRIES_ENV: &str = "RPC_MAX_RETRIES";
+const RPC_RECEIPT_RETRIES: u32 = 3;
 
 const MONGO_DB_URI: &str = "mongodb://127.0.0.1:27017";
 const MONGO_DB_NAME: &str = "ronin-erc20";
 const MONGO_DB_COLLECTION_NAME: &str = "transfers";
+const MONGO_DB_CONTRACTS_COLLECTION_NAME: &str = "contracts";
+
+const MONGO_URI_ENV: &str = "MONGO_URI";
+const MONGO_DB_ENV: &str = "MONGO_DB";
+const MONGO_COLLECTION_ENV: &str = "MONGO_COLLECTION";
+
+const DEFAULT_BATCH_SIZE: usize = 15000;
+const INSERT_RETRIES: u32 = 5;
+const INSERT_BACKOFF: Duration = Duration::from_secs(1);
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+const RPC_STATS_INTERVAL: Duration = Duration::from_secs(60);
+const PROGRESS_RATE_WINDOW: Duration = Duration::from_secs(60);
+/// Target of the log line summarizing a run, which `--quiet` keeps.
+const SUMMARY_TARGET: &str = "erc20::summary";
 
-const MONGO_BATCH_SIZE: usize = 15000;
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(3);
+const BLOCK_TIME_SAMPLE: u64 = 100;
 
-#[derive(Serialize, Deserialize)]
-pub struct Contract {
-    pub name: &'static str,
-    pub decimals: usize,
-    pub erc: ContractType,
-    pub address: &'static str,
+// Blocks per worker fetched before the workers drain, so reloaded contracts and the chain head are picked up.
+const WORKER_WINDOW_BLOCKS: u64 = 32;
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+enum Mode {
+    /// Walk blocks sequentially until `--confirmations` blocks behind the chain head, then exit unless `--follow` is set
+    Backfill,
+    /// Backfill, then follow the chain by subscribing to new heads
+    Subscribe,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line, for log aggregation
+    Json,
 }
 
-#[derive(Serialize, Deserialize)]
-struct Output {
-    transfers: Vec<Transfer>
+#[derive(Subcommand)]
+enum Command {
+    /// Serve indexed transfers from MongoDB over HTTP
+    Serve {
+        /// Port to listen on
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+    },
+    /// Discover ERC20 and ERC721 contracts from Transfer logs and store them in MongoDB
+    Discover {
+        /// First block to scan
+        #[arg(long, default_value_t = 0)]
+        from_block: u64,
+        /// Last block to scan
+        #[arg(long)]
+        to_block: u64,
+        /// Number of blocks per eth_getLogs request
+        #[arg(long, default_value_t = 2000, value_parser = clap::value_parser!(u64).range(1..))]
+        chunk_size: u64,
+    },
+    /// Export indexed transfers from MongoDB to a CSV or NDJSON file
+    Export {
+        /// File to write
+        #[arg(long)]
+        output: PathBuf,
+        /// Format of the written file
+        #[arg(long, value_enum, default_value = "csv")]
+        format: ExportFormat,
+        /// Only export transfers of this contract
+        #[arg(long)]
+        contract: Option<String>,
+        /// First block to export
+        #[arg(long)]
+        from_block: Option<u64>,
+        /// Last block to export
+        #[arg(long)]
+        to_block: Option<u64>,
+    },
+    /// Report blocks missing from the block_stats collection in MongoDB
+    Verify {
+        /// First block to check
+        #[arg(long, default_value_t = 0)]
+        from_block: u64,
+        /// Last block to check, defaults to the checkpoint
+        #[arg(long)]
+        to_block: Option<u64>,
+        /// Rewind the checkpoint to just before the first gap so the next run reindexes from there
+        #[arg(long)]
+        requeue: bool,
+    },
+    /// Re-derive from, to, value and value_decimal of transfers stored with --keep-raw from their raw logs. Decodes them
+    /// like the indexer with the Transfer events and decimals of `--contracts`
+    Reparse,
+    /// Insert transfers from a file written by `export --format ndjson` into the `--backend` database, e.g. to restore a
+    /// backup or migrate between databases without rescanning the chain. Already stored transfers are skipped
+    Import {
+        /// NDJSON file to read
+        #[arg(long)]
+        input: PathBuf,
+        /// Number of transfers per insert
+        #[arg(long, default_value_t = DEFAULT_BATCH_SIZE, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        batch_size: usize,
+    },
+    /// Replay the batches written to `--spool-dir` while the `--backend` database was failing, in block order, deleting
+    /// each file once it's stored
+    ImportSpool {
+        /// Directory the indexer spooled batches to
+        #[arg(long)]
+        spool_dir: PathBuf,
+        /// Number of transfers, approvals or events per insert
+        #[arg(long, default_value_t = DEFAULT_BATCH_SIZE, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        batch_size: usize,
+    },
+    /// Delete the stored transfers of a block and index it again, e.g. to correct a block after fixing a data issue.
+    /// Parses like the indexer with `--contracts`, `--chain-id`, `--address-case`, `--keep-raw`, `--record-gas` and
+    /// `--flatten-receipts`. Block stats, address activity and daily volume are left as they are
+    ReindexBlock {
+        /// Block to index again
+        block: u64,
+    },
+    /// Delete transfers below a block height from the `--backend` database in batches, to limit the storage of databases
+    /// that only need recent history. Block stats, address activity and daily volume are kept, so they still cover pruned
+    /// blocks
+    #[command(group(ArgGroup::new("before").args(["before_block", "before_timestamp"]).required(true)))]
+    Prune {
+        /// Delete transfers of blocks below this one
+        #[arg(long)]
+        before_block: Option<u64>,
+        /// Delete transfers of blocks older than this unix timestamp in seconds
+        #[arg(long)]
+        before_timestamp: Option<u64>,
+        /// Number of transfers per delete
+        #[arg(long, default_value_t = DEFAULT_BATCH_SIZE, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        batch_size: usize,
+    },
+    /// Reconstruct the balance of an address in a token from the transfers in MongoDB, e.g. to check the index against
+    /// the chain. Only covers the indexed blocks, the decimals are taken from `--contracts`
+    Balance {
+        /// Address to sum the transfers of
+        #[arg(long)]
+        address: String,
+        /// Token contract, it has to be watched
+        #[arg(long)]
+        contract: String,
+        /// Last block to include, defaults to every indexed block
+        #[arg(long)]
+        at_block: Option<u64>,
+    },
+    /// Compare the transfers indexed per second of the default block walk and `--get-logs` over a sample range, without
+    /// writing anything. Uses `--contracts`, `--workers`, `--receipt-concurrency` and `--logs-chunk-size`
+    Bench {
+        /// First block of the sample range
+        #[arg(long)]
+        from_block: u64,
+        /// Last block of the sample range
+        #[arg(long)]
+        to_block: u64,
+    },
 }
+
+#[derive(Parser)]
+#[command(about = "Indexes ERC20 transfers into MongoDB, PostgreSQL, SQLite, Kafka or Parquet files")]
+#[command(group(ArgGroup::new("range_end").args(["end_block", "to_timestamp"])))]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Comma separated list of WebSocket or HTTP RPC endpoints, failed over in order. `--mode subscribe` needs WebSocket
+    #[arg(long = "rpc", env = "RPC_URLS", value_delimiter = ',', default_value = RPC_URL, global = true)]
+    rpc_urls: Vec<String>,
+    /// Maximum number of RPC requests per second, unlimited by default
+    #[arg(long, global = true)]
+    rps: Option<NonZeroU32>,
+    /// MongoDB write concern `w`, a number of nodes or `majority`. `1` is the fastest for backfills, `majority` survives
+    /// a primary failover and is safer at the chain head. Defaults to the server's default
+    #[arg(long, env = "MONGO_W", value_parser = parse_acknowledgment, global = true)]
+    mongo_w: Option<Acknowledgment>,
+    /// Acknowledge MongoDB writes only once they reach the on-disk journal, slower but durable across a crash of the server
+    #[arg(long, global = true)]
+    mongo_journal: bool,
+    /// Maximum number of connections to MongoDB, defaults to the driver's 10
+    #[arg(long, env = "MONGO_POOL_SIZE", global = true)]
+    mongo_pool_size: Option<u32>,
+    /// Store the normalized `value_decimal` of transfers as a MongoDB Decimal128 instead of a string, so amounts can be
+    /// compared and sorted in queries. The raw `value` stays a string. Amounts beyond 34 significant digits are rounded
+    #[arg(long, global = true)]
+    mongo_decimal128: bool,
+    /// Insert MongoDB batches in order, so a failing record stops the insert of the records after it. Unordered inserts
+    /// are faster and keep inserting the valid records of a batch. Duplicates are skipped either way
+    #[arg(long, default_value_t = false, action = clap::ArgAction::Set, global = true)]
+    insert_ordered: bool,
+    /// Appended to the names of all MongoDB output collections, e.g. `ronin` for `transfers_ronin`, so several environments
+    /// or chains can share a database. The contracts collection is shared
+    #[arg(long, env = "MONGO_COLLECTION_SUFFIX", global = true, value_parser = parse_collection_suffix)]
+    collection_suffix: Option<String>,
+    /// Format of log output
+    #[arg(long, value_enum, default_value = "text", global = true)]
+    log_format: LogFormat,
+    /// Only log warnings, errors and the summary at the end of a run, e.g. for cron jobs. Ignored when RUST_LOG is set
+    #[arg(long, global = true)]
+    quiet: bool,
+    /// Case of stored contract, sender, recipient and owner addresses. `serve`, `export` and `reparse` need the same
+    /// value to match stored addresses
+    #[arg(long, value_enum, default_value = "lower", global = true)]
+    address_case: AddressCase,
+    /// Unit of the timestamps stored on transfers, approvals, events and block stats, and of the days of
+    /// --daily-volume. `ms` multiplies the block timestamp, which is in seconds, by 1000. Changing it for an existing
+    /// database mixes units
+    #[arg(long, value_enum, default_value = "ms", global = true)]
+    timestamp_unit: TimestampUnit,
+    /// First block to index when no checkpoint exists
+    #[arg(long, default_value_t = 0)]
+    start_block: u64,
+    /// Start at the earliest deployment block of the watched contracts when it is past `--start-block`, taken from
+    /// `deploy_block` in the watchlist or searched with eth_getCode, which needs an archive node
+    #[arg(long, conflicts_with = "shard")]
+    start_at_deployment: bool,
+    /// Last block to index, defaults to `--confirmations` blocks behind the chain head
+    #[arg(long)]
+    end_block: Option<u64>,
+    /// Start at the first block at or after this unix timestamp in seconds instead of `--start-block`, found by a binary
+    /// search over block headers
+    #[arg(long, conflicts_with = "start_block")]
+    from_timestamp: Option<u64>,
+    /// End at the last block at or before this unix timestamp in seconds instead of `--end-block`
+    #[arg(long)]
+    to_timestamp: Option<u64>,
+    /// JSON file with the contracts to index, defaults to WETH, AXS and SLP
+    #[arg(long)]
+    contracts: Option<PathBuf>,
+    /// Load the contracts to index from the `contracts` collection in MongoDB, e.g. as filled by `discover`
+    #[arg(long, conflicts_with = "contracts")]
+    contracts_from_db: bool,
+    /// Reload `--contracts-from-db` every this many minutes. Contracts added mid-run are indexed from the current block on
+    #[arg(long, requires = "contracts_from_db", value_parser = clap::builder::RangedU64ValueParser::<u64>::new().range(1..))]
+    contracts_reload_interval: Option<u64>,
+    /// Number of blocks to stay behind the chain head as a safety margin against reorgs
+    #[arg(long, default_value_t = 50)]
+    confirmations: u64,
+    /// Fetch only the matching logs with eth_getLogs instead of every block and receipt, for sparse contracts.
+    /// Blocks without matching logs get no block stats and transfers don't record tx_from in this mode
+    #[arg(long)]
+    get_logs: bool,
+    /// Number of blocks per eth_getLogs request, providers cap the range
+    #[arg(long, default_value_t = 2000, value_parser = clap::value_parser!(u64).range(1..))]
+    logs_chunk_size: u64,
+    /// Number of workers fetching and parsing a block each. Their blocks are written and checkpointed in block order
+    #[arg(long, alias = "concurrency", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    workers: u64,
+    /// Maximum number of transaction receipts requested at once, across all workers
+    #[arg(long, default_value_t = 64, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    receipt_concurrency: usize,
+    /// Number of batches waiting to be written before fetching pauses
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u64).range(1..))]
+    pending_batches: u64,
+    /// Delete previously stored transfers of a block whose hash has changed due to a reorg, recording each reorg in the
+    /// reorgs collection. Not supported by the kafka and parquet backends
+    #[arg(long)]
+    reorg_check: bool,
+    /// Comma separated list of events to index
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "transfer")]
+    events: Vec<EventKind>,
+    /// JSON ABI file to decode the events given with --abi-event from, stored generically in the events collection
+    #[arg(long, requires = "abi_events")]
+    abi: Option<PathBuf>,
+    /// Name of an event in the --abi file to index, can be repeated. Takes precedence over the built-in events
+    #[arg(long = "abi-event", requires = "abi")]
+    abi_events: Vec<String>,
+    /// Port to serve Prometheus metrics on
+    #[arg(long)]
+    metrics_port: Option<u16>,
+    /// Port to serve the /healthz and /readyz endpoints on
+    #[arg(long)]
+    health_port: Option<u16>,
+    /// Directory to write batches to as gzip compressed NDJSON when they still fail to insert after all retries, instead
+    /// of exiting. Replay them with `import-spool` once the database is back
+    #[arg(long)]
+    spool_dir: Option<PathBuf>,
+    /// JSON file replaced after every persisted batch with the current block, chain head, total transfers, pending
+    /// batches and update time, for monitoring without an HTTP server
+    #[arg(long)]
+    status_file: Option<PathBuf>,
+    /// Blocks the indexer may lag behind the chain head, including --confirmations, before /readyz fails
+    #[arg(long, default_value_t = 100)]
+    ready_max_lag: u64,
+    /// Warn and count `lag_warnings_total` whenever the indexer is more than this many blocks behind the chain head,
+    /// including --confirmations, after it caught up once while following the chain
+    #[arg(long)]
+    lag_warning_threshold: Option<u64>,
+    /// Only store transfers from or to this address, can be repeated
+    #[arg(long = "watch-address")]
+    watch_addresses: Vec<String>,
+    /// Database to write transfers to
+    #[arg(long, value_enum, default_value = "mongo", global = true)]
+    backend: Backend,
+    /// PostgreSQL connection string, required for the postgres backend
+    #[arg(long, env = "DATABASE_URL", required_if_eq("backend", "postgres"), global = true)]
+    database_url: Option<String>,
+    /// Comma separated Kafka bootstrap servers, required for the kafka backend
+    #[arg(long, env = "KAFKA_BROKERS", required_if_eq("backend", "kafka"), global = true)]
+    kafka_brokers: Option<String>,
+    /// Kafka topic to publish transfers to
+    #[arg(long, env = "KAFKA_TOPIC", default_value = "transfers", global = true)]
+    kafka_topic: String,
+    /// SQLite database file, required for the sqlite backend
+    #[arg(long = "sqlite", env = "SQLITE_PATH", required_if_eq("backend", "sqlite"), global = true)]
+    sqlite_path: Option<PathBuf>,
+    /// Directory to write Parquet files of transfers to, required for the parquet backend
+    #[arg(long, env = "PARQUET_DIR", required_if_eq("backend", "parquet"), global = true)]
+    parquet_dir: Option<PathBuf>,
+    /// How the parquet backend partitions transfers into directories
+    #[arg(long, value_enum, default_value = "day", global = true)]
+    parquet_partition: ParquetPartition,
+    /// Blocks per directory with `--parquet-partition blocks`
+    #[arg(long, default_value_t = 100_000, value_parser = clap::value_parser!(u64).range(1..), global = true)]
+    parquet_partition_blocks: u64,
+    /// How to proceed once the indexer has caught up with the chain head
+    #[arg(long, value_enum, default_value = "backfill")]
+    mode: Mode,
+    /// Keep polling for new blocks after catching up with the chain head instead of exiting
+    #[arg(long, conflicts_with_all = ["once", "end_block", "to_timestamp"])]
+    follow: bool,
+    /// Seconds to wait between head checks while caught up, defaults to the chain's average block time
+    #[arg(long)]
+    poll_interval: Option<u64>,
+    /// Exit after catching up with the chain head instead of following it, e.g. for runs from cron
+    #[arg(long)]
+    once: bool,
+    /// Scan and parse blocks without writing anything to the database
+    #[arg(long)]
+    dry_run: bool,
+    /// Chain id stored on every record and checkpoint so several chains can share a database, e.g. 2020 for Ronin or 1 for Ethereum
+    #[arg(long, global = true)]
+    chain_id: Option<u64>,
+    /// Don't check that the node reports `--chain-id`, or Ronin's chain id when indexing the default contracts
+    #[arg(long)]
+    skip_chain_check: bool,
+    /// Include blocks per second, averaged over the last minute, and the ETA to `--end-block` in progress logs
+    #[arg(long, conflicts_with = "quiet")]
+    verbose: bool,
+    /// Maintain the first and last block and transfer count of every sender and recipient in an `address_activity`
+    /// collection. Counts are added per batch, so blocks indexed twice, e.g. after a crash, are counted twice
+    #[arg(long)]
+    address_activity: bool,
+    /// Maintain the summed normalized value and transfer count of every ERC20 contract per UTC day in a `daily_volume`
+    /// collection, supported by the mongo and postgres backends
+    #[arg(long)]
+    daily_volume: bool,
+    /// Store the gas used and effective gas price of each transfer's transaction, taken from its receipt. Not available
+    /// with --get-logs, which doesn't fetch receipts
+    #[arg(long, conflicts_with = "get_logs")]
+    record_gas: bool,
+    /// Store the number of other logs emitted by each transfer's transaction, of any contract, for router and MEV
+    /// analysis. Not available with --get-logs, which doesn't fetch receipts
+    #[arg(long, conflicts_with = "get_logs")]
+    flatten_receipts: bool,
+    /// Drop transfers with a value of zero, e.g. the spam of airdrop tokens, before they are stored
+    #[arg(long)]
+    skip_zero_value: bool,
+    /// Store the raw topics and data of each transfer log so it can be reparsed later
+    #[arg(long)]
+    keep_raw: bool,
+    /// Number of buffered transfers or approvals that triggers a database write
+    #[arg(long, default_value_t = DEFAULT_BATCH_SIZE, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    batch_size: usize,
+    /// Also write buffered records after this many seconds, even if the batch isn't full
+    #[arg(long)]
+    flush_interval: Option<u64>,
+    /// Only index slice `index/count` of the block range, e.g. 2/8, so several processes can backfill in parallel
+    #[arg(long, requires = "range_end")]
+    shard: Option<Shard>,
+    /// Stop after indexing this many blocks from the start block or checkpoint, e.g. to try a configuration
+    #[arg(long, conflicts_with = "follow", value_parser = clap::value_parser!(u64).range(1..))]
+    max_blocks: Option<u64>,
+    /// Re-index this many blocks before the checkpoint when resuming, in case a crash left the last batch partially
+    /// written. Stored records are skipped as duplicates. Kafka consumers see them again and have to dedupe by id. The
+    /// aggregates of --address-activity and --daily-volume would count them twice
+    #[arg(long, default_value_t = 0, conflicts_with_all = ["address_activity", "daily_volume"])]
+    resume_overlap: u64,
+}
+
 pub fn to_string<T: serde::Serialize>(request: &T) -> String {
     web3::helpers::to_string(request).replace('\"', "")
 }
 
-#[derive(Serialize, Deserialize, Eq, PartialEq, Hash, Debug, Clone)]
-pub enum ContractType {
-    ERC20
+/// Lowercases an address and strips its `0x` prefix so differently formatted addresses compare equal.
+fn normalize_address(address: &str) -> String {
+    address.trim_start_matches("0x").to_lowercase()
+}
+
+/// The last block that is `confirmations` behind the chain head, or `None` while there is nothing to index
+/// at `current_block` yet, e.g. on a young chain whose head is still below the margin.
+fn confirmed_stop_block(chain_head_block: u64, confirmations: u64, current_block: u64) -> Option<u64> {
+    let stop_block = chain_head_block.saturating_sub(confirmations);
+
+    (stop_block > 0 && stop_block >= current_block).then_some(stop_block)
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct Transfer {
-    contract: String,
-    from: String,
-    to: String,
-    value: String,
-    timestamp: u64
+fn env_or(key: &str, default: &str) -> String {
+    std::env::var(key).unwrap_or_else(|_| default.to_string())
 }
 
-pub struct IndexModel {
-    pub model: Document,
-    pub options: IndexOptions,
+fn rpc_max_retries() -> Option<u32> {
+    std::env::var(RPC_MAX_RETRIES_ENV)
+        .ok()
+        .map(|v| v.parse::<u32>().unwrap_or_else(|_| panic!("{} must be a number, got {}", RPC_MAX_RETRIES_ENV, v)))
 }
 
-fn index_model(key: &'static str, unique: bool) -> IndexModel {
-    let mut doc = Document::new();
-    doc.insert(key, 1u32);
+struct MongoConfig {
+    uri: String,
+    db_name: String,
+    collection_name: String,
+    collection_suffix: Option<String>,
+}
 
-    IndexModel {
-        model: doc,
-        options: match unique {
-            true => IndexOptions::builder().unique(true).build(),
-            false => Default::default(),
-        },
+impl MongoConfig {
+    fn from_env(collection_suffix: Option<&str>) -> MongoConfig {
+        MongoConfig {
+            uri: env_or(MONGO_URI_ENV, MONGO_DB_URI),
+            db_name: env_or(MONGO_DB_ENV, MONGO_DB_NAME),
+            collection_name: env_or(MONGO_COLLECTION_ENV, MONGO_DB_COLLECTION_NAME),
+            collection_suffix: collection_suffix.map(str::to_string),
+        }
+    }
+
+    /// The transfers collection with `--collection-suffix` applied.
+    fn transfers_collection(&self) -> String {
+        suffixed_collection_name(&self.collection_name, self.collection_suffix.as_deref())
+    }
+
+    /// Client options for writing, with the configured write concern applied to every insert.
+    async fn client_options(&self, w: Option<Acknowledgment>, journal: bool, max_pool_size: Option<u32>) -> ClientOptions {
+        let mut options = ClientOptions::parse(&self.uri)
+            .await
+            .unwrap_or_else(|e| panic!("Invalid mongodb uri {}: {}", self.uri, e));
+
+        if w.is_some() || journal {
+            options.write_concern = Some(WriteConcern::builder().w(w).journal(journal.then_some(true)).build());
+        }
+
+        if max_pool_size.is_some() {
+            options.max_pool_size = max_pool_size;
+        }
+
+        options
+    }
+}
+
+/// Collection names can't contain `$` or null characters.
+fn parse_collection_suffix(value: &str) -> Result<String, String> {
+    match value.is_empty() || value.contains(['$', '\0']) {
+        true => Err("must be non-empty and can't contain `$` or null characters".to_string()),
+        false => Ok(value.to_string()),
+    }
+}
+
+/// Unacknowledged writes (`w: 0`) are rejected because the MongoDB driver doesn't support them.
+fn parse_acknowledgment(value: &str) -> Result<Acknowledgment, String> {
+    match value.parse::<u32>() {
+        Ok(0) => Err("unacknowledged writes are not supported by the MongoDB driver, use 1 for the fastest writes".to_string()),
+        Ok(nodes) => Ok(Acknowledgment::Nodes(nodes)),
+        Err(_) => Ok(Acknowledgment::from(value.to_string())),
+    }
+}
+
+/// Resolves once the process receives SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = tokio::signal::ctrl_c();
+
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler");
+
+        tokio::select! {
+            _ = ctrl_c => {}
+            _ = terminate.recv() => {}
+        }
     }
+
+    #[cfg(not(unix))]
+    ctrl_c.await.ok();
 }
 
 #[tokio::main]
 async fn main() {
-    let provider = web3::transports::WebSocket::new("ws://127.0.0.1:8546").await.unwrap();
-    let web3 =  Web3::new(provider);
+    let args = Args::parse();
 
-    let db_client = Client::with_uri_str(MONGO_DB_URI)
-        .await
-        .unwrap_or_else(|_| panic!("Failed to connect to mongodb at {}", MONGO_DB_URI));
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| match args.quiet {
+            true => EnvFilter::new(format!("warn,{}=info", SUMMARY_TARGET)),
+            false => EnvFilter::new("info"),
+        }));
+
+    match args.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().flatten_event(true).init(),
+    }
+
+    match args.command {
+        Some(Command::Serve { port }) => {
+            let mongo = MongoConfig::from_env(args.collection_suffix.as_deref());
+
+            let db_client = Client::with_uri_str(&mongo.uri)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to connect to mongodb at {}: {}", mongo.uri, e));
+
+            api::serve(db_client.database(&mongo.db_name).collection(&mongo.transfers_collection()), args.address_case, port).await;
+        }
+        Some(Command::Discover { from_block, to_block, chunk_size }) => {
+            let mongo = MongoConfig::from_env(args.collection_suffix.as_deref());
+
+            let db_client = Client::with_uri_str(&mongo.uri)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to connect to mongodb at {}: {}", mongo.uri, e));
+
+            let rpc = Rpc::connect(args.rpc_urls.clone(), rpc_max_retries(), args.rps).await;
+            let contracts = discovery::discover(&rpc, from_block, to_block, chunk_size).await;
+
+            discovery::save_contracts(&db_client.database(&mongo.db_name).collection(MONGO_DB_CONTRACTS_COLLECTION_NAME), &contracts)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to save discovered contracts: {}", e));
+
+            info!(contracts = contracts.len(), "Saved discovered contracts");
+        }
+        Some(Command::Export { output, format, contract, from_block, to_block }) => {
+            let mongo = MongoConfig::from_env(args.collection_suffix.as_deref());
+
+            let db_client = Client::with_uri_str(&mongo.uri)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to connect to mongodb at {}: {}", mongo.uri, e));
+
+            let collection = db_client.database(&mongo.db_name).collection(&mongo.transfers_collection());
+            let rows = export::export(&collection, &output, format, args.address_case, contract.as_deref(), from_block, to_block)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to export transfers to {}: {}", output.display(), e));
+
+            info!(rows, output = %output.display(), "Exported transfers");
+        }
+        Some(Command::Verify { from_block, to_block, requeue }) => {
+            let mongo = MongoConfig::from_env(args.collection_suffix.as_deref());
+
+            let checkpoint_suffix = args.chain_id.map(|chain_id| format!("-chain-{}", chain_id)).unwrap_or_default();
+
+            let options = mongo.client_options(args.mongo_w.clone(), args.mongo_journal, args.mongo_pool_size).await;
+
+            let mut sink = MongoSink::connect(options, &mongo.db_name, &mongo.collection_name, mongo.collection_suffix.as_deref(), MongoCollections::default(), &checkpoint_suffix)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to connect to mongodb at {}: {}", mongo.uri, e));
+
+            let to_block = match to_block {
+                Some(to_block) => to_block,
+                None => sink.load_checkpoint()
+                    .await
+                    .unwrap_or_else(|e| panic!("Failed to load checkpoint: {}", e))
+                    .unwrap_or_else(|| Args::command().error(ErrorKind::MissingRequiredArgument, "--to-block is required when there is no checkpoint").exit()),
+            };
+
+            let gaps = sink.missing_blocks(args.chain_id, from_block, to_block)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to scan block stats: {}", e));
+
+            for (first, last) in &gaps {
+                warn!(from_block = first, to_block = last, blocks = last - first + 1, "Missing blocks");
+            }
+
+            info!(from_block, to_block, gaps = gaps.len(), missing = gaps.iter().map(|(first, last)| last - first + 1).sum::<u64>(), "Verified block coverage");
+
+            match gaps.first() {
+                Some((0, _)) if requeue => warn!("The first gap starts at block 0, reindex it with --start-block 0 after removing the checkpoint"),
+                Some((first, _)) if requeue => {
+                    sink.save_checkpoint(first - 1)
+                        .await
+                        .unwrap_or_else(|e| panic!("Failed to save checkpoint: {}", e));
+
+                    info!(block = first, "Rewound the checkpoint to the first gap");
+                }
+                _ => {}
+            }
+        }
+        Some(Command::Reparse) => {
+            let mongo = MongoConfig::from_env(args.collection_suffix.as_deref());
 
-    let db_indexes: Vec<IndexModel> = vec![
-        index_model("contract", false),
-        index_model("from", false),
-        index_model("to", false),
-        index_model("value", false),
-        index_model("timestamp", false)
-    ];
+            let db_client = Client::with_uri_str(&mongo.uri)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to connect to mongodb at {}: {}", mongo.uri, e));
 
-    let db_db = db_client.database(MONGO_DB_NAME);
-    let transfer_collection = db_db.collection::<Transfer>(MONGO_DB_COLLECTION_NAME);
+            let rpc = Rpc::connect(args.rpc_urls.clone(), rpc_max_retries(), args.rps).await;
+            let map = watchlist_contracts(&args, &rpc).await;
 
-    for model in db_indexes {
-            // If indexes exists this will fail silently.
-            transfer_collection.create_index(mongodb::IndexModel::builder().keys(model.model).options(model.options).build(), None).await.ok();
+            let (abi_events, watched_addresses) = (HashMap::new(), HashSet::new());
+            let parser = LogParser::new(&map, &abi_events, &watched_addresses, true, false, args.chain_id, false);
+
+            let collection = db_client.database(&mongo.db_name).collection(&mongo.transfers_collection());
+            let (reparsed, updated) = reparse::reparse(&collection, &parser, args.chain_id, args.address_case, args.mongo_decimal128)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to reparse transfers: {}", e));
+
+            info!(reparsed, updated, "Reparsed transfers");
+        }
+        Some(Command::Balance { ref address, ref contract, at_block }) => {
+            let (address, contract) = (address.to_lowercase(), contract.to_lowercase());
+            for value in [&address, &contract] {
+                if !contracts::is_valid_address(value) {
+                    Args::command().error(ErrorKind::InvalidValue, format!("Invalid address {:?}, expected a 0x-prefixed 40 character hex string", value)).exit();
+                }
+            }
+
+            let rpc = Rpc::connect(args.rpc_urls.clone(), rpc_max_retries(), args.rps).await;
+            let map = watchlist_contracts(&args, &rpc).await;
+            let Some(token) = map.get(&contract) else {
+                Args::command().error(ErrorKind::InvalidValue, format!("{} is not watched, list it in --contracts for its decimals", contract)).exit();
+            };
+
+            // ERC721 values are token ids, summing them means nothing.
+            if token.erc != ContractType::ERC20 {
+                Args::command().error(ErrorKind::InvalidValue, format!("{} is not an ERC20 token, balances are only reconstructed for ERC20", contract)).exit();
+            }
+
+            let mongo = MongoConfig::from_env(args.collection_suffix.as_deref());
+
+            let checkpoint_suffix = args.chain_id.map(|chain_id| format!("-chain-{}", chain_id)).unwrap_or_default();
+
+            let options = mongo.client_options(args.mongo_w.clone(), args.mongo_journal, args.mongo_pool_size).await;
+
+            let sink = MongoSink::connect(options, &mongo.db_name, &mongo.collection_name, mongo.collection_suffix.as_deref(), MongoCollections::default(), &checkpoint_suffix)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to connect to mongodb at {}: {}", mongo.uri, e));
+
+            // Without --at-block, up to the last indexed block, so transfers stored past the checkpoint before a crash
+            // aren't counted.
+            let at_block = match at_block {
+                Some(at_block) => Some(at_block),
+                None => sink.load_checkpoint().await.unwrap_or_else(|e| panic!("Failed to load checkpoint: {}", e)),
+            };
+
+            let (balance, transfers) = sink.balance(args.chain_id, &args.address_case.apply(contract.clone()), &args.address_case.apply(address.clone()), at_block)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to sum transfers: {}", e));
+
+            info!(address, contract, name = token.name, at_block, transfers, balance = %balance, balance_decimal = token.normalize_amount(balance.clone()), "Reconstructed balance");
+        }
+        Some(Command::Bench { from_block, to_block }) => {
+            if from_block > to_block {
+                Args::command()
+                    .error(ErrorKind::ValueValidation, format!("--from-block ({}) must not be greater than --to-block ({})", from_block, to_block))
+                    .exit();
+            }
+
+            let rpc = Rpc::connect(args.rpc_urls.clone(), rpc_max_retries(), args.rps).await;
+            let map = watchlist_contracts(&args, &rpc).await;
+
+            let (abi_events, watched_addresses) = (HashMap::new(), HashSet::new());
+            let parser = LogParser::new(&map, &abi_events, &watched_addresses, true, false, args.chain_id, false);
+            let receipt_permits = tokio::sync::Semaphore::new(args.receipt_concurrency);
+            let blocks = to_block - from_block + 1;
+
+            let block_walk = bench::block_walk(&rpc, &parser, &receipt_permits, from_block, to_block, args.workers).await;
+            info!(transfers = block_walk.transfers, duration_secs = block_walk.elapsed.as_secs_f64(), "Benchmarked the block walk");
+
+            let get_logs = bench::get_logs(&rpc, &parser, from_block, to_block, args.logs_chunk_size).await;
+            info!(transfers = get_logs.transfers, duration_secs = get_logs.elapsed.as_secs_f64(), "Benchmarked --get-logs");
+
+            if block_walk.transfers != get_logs.transfers {
+                warn!(block_walk = block_walk.transfers, get_logs = get_logs.transfers, "The backfill paths found a different number of transfers");
+            }
+
+            info!(
+                blocks,
+                block_walk_transfers_per_second = block_walk.per_second(block_walk.transfers as u64),
+                get_logs_transfers_per_second = get_logs.per_second(get_logs.transfers as u64),
+                block_walk_blocks_per_second = block_walk.per_second(blocks),
+                get_logs_blocks_per_second = get_logs.per_second(blocks),
+                faster = if get_logs.elapsed < block_walk.elapsed { "--get-logs" } else { "block walk" },
+                "Backfill comparison"
+            );
+        }
+        Some(Command::Import { ref input, batch_size }) => {
+            let checkpoint_suffix = args.chain_id.map(|chain_id| format!("-chain-{}", chain_id)).unwrap_or_default();
+
+            let mut sink = connect_sink(&args, false, false, &checkpoint_suffix).await;
+
+            let imported = import::import_ndjson(sink.as_mut(), input, batch_size)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to import transfers from {}: {}", input.display(), e));
+
+            info!(imported, input = %input.display(), "Imported transfers");
+        }
+        Some(Command::ImportSpool { ref spool_dir, batch_size }) => {
+            let checkpoint_suffix = args.chain_id.map(|chain_id| format!("-chain-{}", chain_id)).unwrap_or_default();
+
+            let mut sink = connect_sink(&args, true, true, &checkpoint_suffix).await;
+
+            let (files, transfers) = spool::replay(sink.as_mut(), spool_dir, batch_size)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to replay spooled batches from {}: {}", spool_dir.display(), e));
+
+            info!(files, transfers, spool_dir = %spool_dir.display(), "Replayed spooled batches");
+        }
+        Some(Command::ReindexBlock { block }) => {
+            let rpc = Rpc::connect(args.rpc_urls.clone(), rpc_max_retries(), args.rps).await;
+            let map = watchlist_contracts(&args, &rpc).await;
+
+            let (abi_events, watched_addresses) = (HashMap::new(), HashSet::new());
+            let parser = LogParser::new(&map, &abi_events, &watched_addresses, true, false, args.chain_id, args.keep_raw)
+                .with_address_case(args.address_case)
+                .with_timestamp_unit(args.timestamp_unit)
+                .with_gas(args.record_gas)
+                .with_sibling_logs(args.flatten_receipts)
+                .with_skip_zero_value(args.skip_zero_value);
+
+            // Fetch before deleting, so the stored transfers are only missing for the duration of the writes.
+            let mut records = Records::default();
+            let receipt_permits = tokio::sync::Semaphore::new(args.receipt_concurrency);
+            parser.parse_block(&fetch::fetch_block(&rpc, &parser, &receipt_permits, block).await, &mut records);
+
+            let checkpoint_suffix = args.chain_id.map(|chain_id| format!("-chain-{}", chain_id)).unwrap_or_default();
+
+            let mut sink = connect_sink(&args, false, false, &checkpoint_suffix).await;
+
+            let deleted = sink.delete_block(args.chain_id, block)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to delete the transfers of block {}: {}", block, e));
+
+            if !records.transfers.is_empty() {
+                sink.insert_batch(&records.transfers)
+                    .await
+                    .unwrap_or_else(|e| panic!("Failed to insert the transfers of block {}: {}", block, e));
+            }
+
+            sink.flush()
+                .await
+                .unwrap_or_else(|e| panic!("Failed to insert the transfers of block {}: {}", block, e));
+
+            info!(block, deleted, inserted = records.transfers.len(), "Reindexed block");
+        }
+        Some(Command::Prune { before_block, before_timestamp, batch_size }) => {
+            let before_block = match (before_block, before_timestamp) {
+                (Some(before_block), _) => before_block,
+                (None, Some(timestamp)) => {
+                    let rpc = Rpc::connect(args.rpc_urls.clone(), rpc_max_retries(), args.rps).await;
+
+                    match fetch::first_block_at(&rpc, timestamp).await {
+                        Some(block) => block,
+                        // Every block is older than the timestamp.
+                        None => rpc.call(|eth| eth.block_number()).await.as_u64() + 1,
+                    }
+                }
+                (None, None) => unreachable!("clap requires --before-block or --before-timestamp"),
+            };
+
+            let checkpoint_suffix = args.chain_id.map(|chain_id| format!("-chain-{}", chain_id)).unwrap_or_default();
+
+            let mut sink = connect_sink(&args, false, false, &checkpoint_suffix).await;
+
+            let mut pruned = 0;
+            loop {
+                let deleted = sink.prune_transfers(args.chain_id, before_block, batch_size)
+                    .await
+                    .unwrap_or_else(|e| panic!("Failed to prune transfers: {}", e));
+
+                pruned += deleted;
+                if deleted < batch_size as u64 {
+                    break;
+                }
+
+                info!(deleted, pruned, "Pruned a batch of transfers");
+            }
+
+            info!(pruned, before_block, "Pruned transfers");
+        }
+        None => run_indexer(args).await,
     }
+}
+
+/// The contracts of the `--contracts` watchlist with their metadata fetched, or the default contracts.
+async fn watchlist_contracts(args: &Args, rpc: &Rpc) -> HashMap<String, Contract> {
+    match &args.contracts {
+        Some(path) => {
+            let entries = contracts::load_contracts(path).unwrap_or_else(|e| Args::command().error(ErrorKind::InvalidValue, e).exit());
+            token::resolve_contracts(rpc, entries).await
+        }
+        None => contracts::default_contracts(),
+    }
+}
+
+/// Connects to the `--backend` database, or a sink that only logs with `--dry-run`. `approvals` and `events` create the
+/// collections for approvals and ABI events.
+async fn connect_sink(args: &Args, approvals: bool, events: bool, checkpoint_suffix: &str) -> Box<dyn TransferSink> {
+    match args.backend {
+        _ if args.dry_run => Box::new(DryRunSink::default()),
+        Backend::Mongo => {
+            let mongo = MongoConfig::from_env(args.collection_suffix.as_deref());
+
+            let options = mongo.client_options(args.mongo_w.clone(), args.mongo_journal, args.mongo_pool_size).await;
+
+            let collections = MongoCollections {
+                approvals,
+                events,
+                address_activity: args.address_activity,
+                daily_volume: args.daily_volume,
+            };
 
-    let mut map = HashMap::new();
+            let sink = MongoSink::connect(options, &mongo.db_name, &mongo.collection_name, mongo.collection_suffix.as_deref(), collections, checkpoint_suffix)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to connect to mongodb at {}: {}", mongo.uri, e))
+                .with_decimal128(args.mongo_decimal128)
+                .with_ordered_inserts(args.insert_ordered);
+
+            Box::new(sink)
+        }
+        Backend::Postgres => {
+            let database_url = args.database_url.as_deref().expect("--database-url is required for the postgres backend");
+
+            let sink = PostgresSink::connect(database_url, checkpoint_suffix)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to connect to postgres: {}", e));
+
+            Box::new(sink)
+        }
+        Backend::Kafka => {
+            let brokers = args.kafka_brokers.as_deref().expect("--kafka-brokers is required for the kafka backend");
+
+            let sink = KafkaSink::connect(brokers, &args.kafka_topic)
+                .unwrap_or_else(|e| panic!("Failed to create kafka producer for {}: {}", brokers, e));
+
+            Box::new(sink)
+        }
+        Backend::Sqlite => {
+            let path = args.sqlite_path.as_deref().expect("--sqlite is required for the sqlite backend");
 
-    #[derive(Serialize, Deserialize)]
-    pub struct Contract {
-        pub name: &'static str,
-        pub decimals: usize,
-        pub erc: ContractType,
-        pub address: &'static str,
+            let sink = SqliteSink::open(path, checkpoint_suffix)
+                .unwrap_or_else(|e| panic!("Failed to open sqlite database {}: {}", path.display(), e));
+
+            Box::new(sink)
+        }
+        Backend::Parquet => {
+            let dir = args.parquet_dir.as_deref().expect("--parquet-dir is required for the parquet backend");
+
+            let sink = ParquetSink::open(dir, args.parquet_partition, args.parquet_partition_blocks, args.timestamp_unit, checkpoint_suffix)
+                .unwrap_or_else(|e| panic!("Failed to open parquet directory {}: {}", dir.display(), e));
+
+            Box::new(sink)
+        }
     }
+}
 
-    map.insert(
-        "0xc99a6a985ed2cac1ef41640596c5a5f9f4e19ef5",
-        Contract {
-            name: "WETH",
-            decimals: 18,
-            erc: ERC20,
-            address: "0xc99a6a985ed2cac1ef41640596c5a5f9f4e19ef5",
-        },
+async fn run_indexer(args: Args) {
+    if let Some(end_block) = args.end_block {
+        if args.start_block > end_block {
+            Args::command()
+                .error(ErrorKind::ValueValidation, format!("--start-block ({}) must not be greater than --end-block ({})", args.start_block, end_block))
+                .exit();
+        }
+    }
+
+    if let (Some(from_timestamp), Some(to_timestamp)) = (args.from_timestamp, args.to_timestamp) {
+        if from_timestamp > to_timestamp {
+            Args::command()
+                .error(ErrorKind::ValueValidation, format!("--from-timestamp ({}) must not be greater than --to-timestamp ({})", from_timestamp, to_timestamp))
+                .exit();
+        }
+    }
+
+    let checkpoint_suffix = format!(
+        "{}{}",
+        args.chain_id.map(|chain_id| format!("-chain-{}", chain_id)).unwrap_or_default(),
+        args.shard.map(|shard| shard.checkpoint_suffix()).unwrap_or_default()
     );
 
-    map.insert(
-        "0xed4a9f48a62fb6fdcfb45bb00c9f61d1a436e58c",
-        Contract {
-            name: "AXS",
-            decimals: 18,
-            erc: ERC20,
-            address: "0xed4a9f48a62fb6fdcfb45bb00c9f61d1a436e58c",
+    let index_transfers = args.events.contains(&EventKind::Transfer);
+    let index_approvals = args.events.contains(&EventKind::Approval);
+
+    if index_approvals && args.backend != Backend::Mongo && !args.dry_run {
+        Args::command()
+            .error(ErrorKind::ArgumentConflict, "Approval events are only supported by the mongo backend")
+            .exit();
+    }
+
+    let abi_events = match &args.abi {
+        Some(path) => events::load_abi_events(path, &args.abi_events)
+            .unwrap_or_else(|e| Args::command().error(ErrorKind::InvalidValue, e).exit()),
+        None => HashMap::new(),
+    };
+
+    if !abi_events.is_empty() && args.backend != Backend::Mongo && !args.dry_run {
+        Args::command()
+            .error(ErrorKind::ArgumentConflict, "ABI events are only supported by the mongo backend")
+            .exit();
+    }
+
+    if args.keep_raw && args.backend != Backend::Mongo && !args.dry_run {
+        Args::command()
+            .error(ErrorKind::ArgumentConflict, "--keep-raw is only supported by the mongo backend")
+            .exit();
+    }
+
+    if args.address_activity && matches!(args.backend, Backend::Kafka | Backend::Parquet) && !args.dry_run {
+        Args::command()
+            .error(ErrorKind::ArgumentConflict, "--address-activity is not supported by the kafka and parquet backends")
+            .exit();
+    }
+
+    if args.reorg_check && matches!(args.backend, Backend::Kafka | Backend::Parquet) && !args.dry_run {
+        Args::command()
+            .error(ErrorKind::ArgumentConflict, "--reorg-check is not supported by the kafka and parquet backends")
+            .exit();
+    }
+
+    if let (Mode::Subscribe, Some(url)) = (args.mode, args.rpc_urls.iter().find(|url| rpc::is_http(url))) {
+        Args::command()
+            .error(ErrorKind::ArgumentConflict, format!("--mode subscribe needs WebSocket endpoints, {} is HTTP and can't subscribe to new heads", url))
+            .exit();
+    }
+
+    if args.daily_volume && !matches!(args.backend, Backend::Mongo | Backend::Postgres) && !args.dry_run {
+        Args::command()
+            .error(ErrorKind::ArgumentConflict, "--daily-volume is only supported by the mongo and postgres backends")
+            .exit();
+    }
+
+    let watchlist = args.contracts.as_ref().map(|path| {
+        contracts::load_contracts(path).unwrap_or_else(|e| Args::command().error(ErrorKind::InvalidValue, e).exit())
+    });
+
+    let watched_addresses: HashSet<String> = args.watch_addresses
+        .iter()
+        .map(|address| normalize_address(address))
+        .collect();
+
+    if let Some(port) = args.metrics_port {
+        tokio::spawn(metrics::serve(port));
+    }
+
+    if let Some(port) = args.health_port {
+        tokio::spawn(health::serve(port, args.ready_max_lag));
+    }
+
+    let rpc = Rpc::connect(args.rpc_urls.clone(), rpc_max_retries(), args.rps).await;
+
+    // Records of the wrong chain are hard to tell apart once stored, so refuse to start on a mismatch.
+    let expected_chain_id = args.chain_id.or((watchlist.is_none() && !args.contracts_from_db).then_some(contracts::RONIN_CHAIN_ID));
+    if let (Some(expected_chain_id), false) = (expected_chain_id, args.skip_chain_check) {
+        let chain_id = rpc.call(|eth| eth.chain_id()).await.as_u64();
+
+        if chain_id != expected_chain_id {
+            error!(chain_id, expected_chain_id, "Connected node is on a different chain, use --skip-chain-check to index it anyway");
+            std::process::exit(1);
+        }
+    }
+
+    let start_block = match args.from_timestamp {
+        Some(timestamp) => match fetch::first_block_at(&rpc, timestamp).await {
+            Some(block) => {
+                info!(timestamp, block, "Starting at the first block of --from-timestamp");
+                block
+            }
+            None => {
+                info!(timestamp, "--from-timestamp is past the chain head, nothing to index");
+                return;
+            }
         },
-    );
+        None => args.start_block,
+    };
 
-    map.insert(
-        "0xa8754b9fa15fc18bb59458815510e40a12cd2014",
-        Contract {
-            name: "SLP",
-            decimals: 0,
-            erc: ERC20,
-            address: "0xa8754b9fa15fc18bb59458815510e40a12cd2014",
+    let end_block = match args.to_timestamp {
+        Some(timestamp) => match fetch::first_block_at(&rpc, timestamp + 1).await {
+            Some(0) => {
+                info!(timestamp, "--to-timestamp is before the first block, nothing to index");
+                return;
+            }
+            Some(block) => {
+                info!(timestamp, block = block - 1, "Ending at the last block of --to-timestamp");
+                Some(block - 1)
+            }
+            // The chain head may still be followed by blocks within the timestamp, stop at the head anyway.
+            None => Some(rpc.call(|eth| eth.block_number()).await.as_u64()),
         },
-    );
+        None => args.end_block,
+    };
+
+    if end_block.is_some_and(|end_block| start_block > end_block) {
+        info!(start_block, end_block, "The start block is past the end block, nothing to index");
+        return;
+    }
+
+    let (start_block, end_block) = match (args.shard, end_block) {
+        (Some(shard), Some(end_block)) => match shard.range(start_block, end_block) {
+            Some((start_block, end_block)) => {
+                info!(%shard, start_block, end_block, "Indexing shard");
+                (start_block, Some(end_block))
+            }
+            None => {
+                info!(%shard, "Shard is empty, nothing to index");
+                return;
+            }
+        },
+        _ => (start_block, end_block),
+    };
+
+    let contracts_collection = match args.contracts_from_db {
+        true => {
+            let mongo = MongoConfig::from_env(args.collection_suffix.as_deref());
 
-    let event = Event {
-        name: "Transfer".to_string(),
-        inputs: vec![
-            EventParam {
-                name: "_from".to_string(),
-                kind: ParamType::Address,
-                indexed: true,
-            },
-            EventParam {
-                name: "_to".to_string(),
-                kind: ParamType::Address,
-                indexed: true,
-            },
-            EventParam {
-                name: "_value".to_string(),
-                kind: ParamType::Uint(256),
-                indexed: false,
-            },
-        ],
-        anonymous: false,
+            let db_client = Client::with_uri_str(&mongo.uri)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to connect to mongodb at {}: {}", mongo.uri, e));
+
+            Some(db_client.database(&mongo.db_name).collection::<Contract>(MONGO_DB_CONTRACTS_COLLECTION_NAME))
+        }
+        false => None,
     };
 
+    let mut map = match (watchlist, &contracts_collection) {
+        (Some(entries), _) => token::resolve_contracts(&rpc, entries).await,
+        (None, Some(collection)) => {
+            let map = discovery::load_contracts(collection)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to load contracts from mongodb: {}", e));
+
+            if map.is_empty() {
+                error!("The contracts collection is empty, nothing to index");
+                std::process::exit(1);
+            }
+
+            info!(contracts = map.len(), "Loaded contracts from the database");
+            map
+        }
+        (None, None) => contracts::default_contracts(),
+    };
+
+    let start_block = match args.start_at_deployment {
+        true => match token::earliest_deploy_block(&rpc, &map).await {
+            Some(deploy_block) if deploy_block > start_block => {
+                info!(block = deploy_block, "Starting at the earliest deployment block");
+                deploy_block
+            }
+            _ => start_block,
+        },
+        false => start_block,
+    };
+
+    if end_block.is_some_and(|end_block| start_block > end_block) {
+        info!(start_block, "Watched contracts were deployed after --end-block, nothing to index");
+        return;
+    }
+
+    let sink = connect_sink(&args, index_approvals, !abi_events.is_empty(), &checkpoint_suffix).await;
+
+    let mut parser = LogParser::new(&map, &abi_events, &watched_addresses, index_transfers, index_approvals, args.chain_id, args.keep_raw)
+        .with_address_case(args.address_case)
+        .with_timestamp_unit(args.timestamp_unit)
+        .with_gas(args.record_gas)
+        .with_sibling_logs(args.flatten_receipts)
+        .with_skip_zero_value(args.skip_zero_value);
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    {
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            info!("Received shutdown signal, stopping after the current block");
+            shutdown.store(true, Ordering::SeqCst);
+        });
+    }
+
     let mut stop = false;
-    let mut current_block = 0u64;
+    let checkpoint = sink
+        .load_checkpoint()
+        .await
+        .unwrap_or_else(|e| panic!("Failed to load checkpoint: {}", e));
 
-    let mut transfer_storage: Vec<Transfer> = vec![];
+    let mut current_block = match checkpoint {
+        Some(last_block) if args.resume_overlap > 0 => {
+            let resume_block = (last_block + 1).saturating_sub(args.resume_overlap).max(start_block);
+            info!(block = last_block, resume_block, "Resuming from checkpoint, re-indexing --resume-overlap blocks");
+            resume_block
+        }
+        Some(last_block) => {
+            info!(block = last_block, "Resuming from checkpoint");
+            last_block + 1
+        }
+        None => start_block,
+    };
+
+    // Unlike --end-block this counts from where the run starts, which may be a checkpoint.
+    let max_end_block = args.max_blocks.map(|max_blocks| current_block + max_blocks - 1);
+
+    let (first_block, started) = (current_block, Instant::now());
+
+    let (batches, pending_batches) = tokio::sync::mpsc::channel(args.pending_batches as usize);
+    let writer = tokio::spawn(
+        Writer::new(sink, args.chain_id, args.address_activity, args.batch_size)
+            .with_status_file(args.status_file.clone())
+            .with_spool_dir(args.spool_dir.clone())
+            .run(pending_batches),
+    );
+
+    let mut records = Records::default();
+    let mut block_stats_storage: Vec<BlockStats> = vec![];
+    let mut batch_blocks: Vec<(u64, String)> = vec![];
+
+    let mut heads = None;
+
+    // Set once the indexer caught up while following the chain, lag during the backfill is expected.
+    let mut following = false;
+    let mut last_lag = 0;
 
     let mut total_transfers: u64 = 0;
+    // Transfers of the batches sent after the shutdown signal, for the shutdown log.
+    let mut flushed_transfers: u64 = 0;
+    // Keyed by contract address as stored, for the summary at the end of the run.
+    let mut contract_transfers: BTreeMap<String, u64> = BTreeMap::new();
+    let mut last_progress = Instant::now();
+    let mut last_rpc_stats = Instant::now();
+    let mut block_rate = BlockRate::new(PROGRESS_RATE_WINDOW);
 
-    loop {
+    let flush_interval = args.flush_interval.map(Duration::from_secs);
+    let mut last_flush = Instant::now();
 
-        let chain_head_block = web3
-            .eth()
-            .block_number()
-            .await
-            .expect("Failed to retrieve head block number from chain!");
+    // Measured the first time the indexer catches up, backfills never need it.
+    let mut poll_interval = args.poll_interval.map(Duration::from_secs);
 
-        let stream_stop_block: u64 = chain_head_block.as_u64() - 50;
+    let receipt_permits = tokio::sync::Semaphore::new(args.receipt_concurrency);
 
-        let block = web3.eth()
-            .block_with_txs(BlockId::Number(BlockNumber::from(current_block as u64)))
-            .await
-            .unwrap_or_else(|_| panic!("Failed to load block {} from provider!", current_block))
-            .unwrap_or_else(|| panic!("Failed to unwrap block {} from result!", current_block));
-
-        let timestamp = block.timestamp.as_u64() * 1000;
-
-        let contracts: Vec<&str> = map
-            .values()
-            .filter(|c| c.erc == ERC20)
-            .map(|c| c.address)
-            .collect();
-
-        for tx in block.transactions {
-
-
-            if let Some(tx_to) = tx.to {
-                let tx_to = to_string(&tx_to);
-
-                let receipt = web3.eth().transaction_receipt(tx.hash).await.unwrap().unwrap();
-                let transfer_log = receipt
-                    .logs
-                    .iter()
-                    .filter(|x| {
-                        to_string(&x.topics[0]) == ERC_TRANSFER_TOPIC
-                            && contracts.contains(&to_string(&x.address).as_str())
-                    })
-                    .collect::<Vec<&Log>>();
-
-                for transfer in transfer_log {
-                    let data = event.parse_log(RawLog {
-                        topics: transfer.to_owned().topics,
-                        data: transfer.to_owned().data.0,
-                    }).unwrap();
-
-                    let from = to_string(&data.params[0].value.to_string());
-                    let to = to_string(&data.params[1].value.to_string());
-                    let value = to_string(&data.params[2].value.to_string());
-
-                    transfer_storage.push(Transfer {
-                        contract: tx_to.clone(),
-                        from,
-                        to,
-                        value,
-                        timestamp
-                    });
+    let contracts_reload_interval = args.contracts_reload_interval.map(|minutes| Duration::from_secs(minutes * 60));
+    let mut last_contracts_reload = Instant::now();
 
+    loop {
+        if let (Some(collection), Some(interval)) = (&contracts_collection, contracts_reload_interval) {
+            if last_contracts_reload.elapsed() >= interval {
+                last_contracts_reload = Instant::now();
+
+                match discovery::load_contracts(collection).await {
+                    Ok(reloaded) if reloaded.is_empty() => warn!("The contracts collection is empty, keeping the current contracts"),
+                    Ok(reloaded) if reloaded != map => {
+                        info!(contracts = reloaded.len(), block = current_block, "Reloaded contracts");
+                        parser.set_contracts(&reloaded);
+                        map = reloaded;
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!(error = %e, "Failed to reload contracts, keeping the current ones"),
                 }
-            };
+            }
         }
 
-        current_block += 1;
+        let stream_stop_block: u64 = match end_block {
+            Some(end_block) => end_block,
+            None => {
+                let chain_head_block = match heads.as_mut() {
+                    Some(heads) => rpc.next_head(heads).await,
+                    None => rpc.call(|eth| eth.block_number()).await.as_u64(),
+                };
+                METRICS.chain_head_block.set(chain_head_block as i64);
 
-        if current_block > stream_stop_block {
-           stop = true
-        }
+                let lag = chain_head_block.saturating_sub(current_block);
+                if following && args.lag_warning_threshold.is_some_and(|threshold| lag > threshold) {
+                    warn!(lag, growing = lag > last_lag, chain_head_block, current_block, "Falling behind the chain head");
+                    METRICS.lag_warnings.inc();
+                }
+                last_lag = lag;
 
-        if transfer_storage.len() >= MONGO_BATCH_SIZE || stop {
-            total_transfers += transfer_storage.len()  as u64;
-            transfer_collection.insert_many(&transfer_storage, None).await.ok();
+                match confirmed_stop_block(chain_head_block, args.confirmations, current_block) {
+                    Some(stop_block) => stop_block,
+                    // Wait until the next block leaves the safety window, new heads already block until the next one.
+                    None => {
+                        if args.once {
+                            info!(block = current_block, chain_head_block, "Already caught up with the chain head");
+                            break;
+                        }
 
-            transfer_storage.clear();
-        }
+                        if shutdown.load(Ordering::SeqCst) {
+                            break;
+                        }
+
+                        if heads.is_none() {
+                            let interval = match poll_interval {
+                                Some(interval) => interval,
+                                None => {
+                                    let block_time = fetch::block_time(&rpc, chain_head_block, BLOCK_TIME_SAMPLE).await
+                                        .filter(|block_time| !block_time.is_zero())
+                                        .unwrap_or(DEFAULT_POLL_INTERVAL);
+                                    info!(seconds = block_time.as_secs(), "Polling for new blocks at the chain's block time");
+
+                                    *poll_interval.insert(block_time)
+                                }
+                            };
+
+                            debug!(chain_head_block, current_block, "Waiting for the chain head to pass the safety margin");
+                            tokio::time::sleep(interval).await;
+                        }
+
+                        continue;
+                    }
+                }
+            }
+        };
+
+        let stream_stop_block = max_end_block.map_or(stream_stop_block, |max_end_block| stream_stop_block.min(max_end_block));
+
+        // Fetch and parse a window of blocks concurrently but process them in order so checkpoints stay correct.
+        let mut window = match args.get_logs {
+            true => {
+                let window_end = (current_block + args.logs_chunk_size - 1).min(stream_stop_block).max(current_block);
+                let window = fetch::fetch_logs(&rpc, &parser, current_block, window_end).await;
 
-        println!("Block: {:>12} Total Transfer: {:>12} Pending Transfer: {:>6}", current_block.separate_with_commas(), total_transfers.separate_with_commas(), transfer_storage.len().separate_with_commas());
+                stream::iter(window.into_iter().map(|block_logs| fetch::parse_block(&parser, block_logs))).boxed_local()
+            }
+            false => {
+                let window_end = (current_block + args.workers * WORKER_WINDOW_BLOCKS - 1).min(stream_stop_block).max(current_block);
+                fetch::parse_blocks(&rpc, &parser, &receipt_permits, current_block, window_end, args.workers as usize).boxed_local()
+            }
+        };
+
+        while let Some((block_logs, block_records)) = window.next().await {
+            // Blocks without matching logs aren't fetched in --get-logs mode.
+            if let Some(block) = &block_logs.block {
+                let transfer_count = block_records.transfers.len() as u64;
+                records.append(block_records);
+
+                // Orphaned transfers are deleted by the writer, right before the batch replacing them is inserted.
+                if args.reorg_check {
+                    batch_blocks.push((block.number, block.hash.clone()));
+                }
+
+                block_stats_storage.push(BlockStats {
+                    chain_id: args.chain_id,
+                    block_number: block.number,
+                    timestamp: block.timestamp,
+                    transfer_count
+                });
+            }
+
+            current_block = block_logs.number + 1;
+            METRICS.blocks_processed.inc();
+            METRICS.current_block.set(current_block as i64);
+            HEALTH.block_processed.store(true, Ordering::SeqCst);
+
+            let caught_up = current_block > stream_stop_block;
+
+            if max_end_block.is_some_and(|max_end_block| current_block > max_end_block) {
+                info!(block = current_block - 1, max_blocks = args.max_blocks, "Indexed --max-blocks blocks, stopping");
+                stop = true
+            } else if caught_up && args.mode == Mode::Subscribe && end_block.is_none() && !args.once {
+                following = true;
+                if heads.is_none() {
+                    info!(block = current_block, "Caught up with the chain head, subscribing to new heads");
+                    heads = Some(rpc.subscribe_new_heads().await);
+                }
+            } else if caught_up && args.follow {
+                following = true;
+                debug!(block = current_block, "Caught up with the chain head, polling for new blocks");
+            } else if caught_up {
+                stop = true
+            }
+
+            if shutdown.load(Ordering::SeqCst) {
+                stop = true
+            }
+
+            let flush_due = flush_interval.is_some_and(|interval| last_flush.elapsed() >= interval);
+
+            if records.transfers.len() >= args.batch_size || records.approvals.len() >= args.batch_size || records.events.len() >= args.batch_size || flush_due || caught_up || stop {
+                last_flush = Instant::now();
+
+                let dropped = dedup_transfers(&mut records.transfers);
+                if dropped > 0 {
+                    warn!(dropped, "Dropped duplicate transfers from batch");
+                }
+
+                total_transfers += records.transfers.len() as u64;
+                if shutdown.load(Ordering::SeqCst) {
+                    flushed_transfers += records.transfers.len() as u64;
+                }
+                for transfer in &records.transfers {
+                    *contract_transfers.entry(transfer.contract.clone()).or_insert(0) += 1;
+                }
+
+                // ERC721 values are token ids, summing them means nothing.
+                let daily_volume = match args.daily_volume {
+                    true => models::daily_volume(args.chain_id, args.timestamp_unit, records.transfers.iter().filter(|transfer| {
+                        map.get(&transfer.contract.to_lowercase()).is_some_and(|contract| contract.erc == ContractType::ERC20)
+                    })),
+                    false => vec![],
+                };
+
+                let batch = Batch {
+                    daily_volume,
+                    records: std::mem::take(&mut records),
+                    block_stats: std::mem::take(&mut block_stats_storage),
+                    blocks: std::mem::take(&mut batch_blocks),
+                    last_block: current_block - 1,
+                };
+
+                // Waits while the writer is `--pending-batches` behind, pausing fetching until the sink catches up.
+                if batches.send(batch).await.is_err() {
+                    error!("Writer stopped unexpectedly, exiting");
+                    std::process::exit(1);
+                }
+            }
+
+            debug!(block = current_block, total_transfers, pending = records.transfers.len(), "Processed block");
+
+            if args.verbose {
+                block_rate.record(Instant::now(), current_block);
+            }
+
+            if last_progress.elapsed() >= PROGRESS_INTERVAL || stop {
+                match args.verbose {
+                    true => {
+                        let blocks_per_second = block_rate.blocks_per_second().map(|rate| (rate * 10.0).round() / 10.0);
+                        let eta_secs = end_block.and_then(|end_block| block_rate.eta((end_block + 1).saturating_sub(current_block))).map(|eta| eta.as_secs());
+
+                        info!(block = current_block, total_transfers, pending = records.transfers.len(), blocks_per_second, eta_secs, "Progress");
+                    }
+                    false => info!(block = current_block, total_transfers, pending = records.transfers.len(), "Progress"),
+                }
+                last_progress = Instant::now();
+            }
+
+            if last_rpc_stats.elapsed() >= RPC_STATS_INTERVAL || stop {
+                rpc.log_endpoint_stats();
+                last_rpc_stats = Instant::now();
+            }
+
+            if stop {
+                break;
+            }
+        }
 
         if stop {
             break;
         }
     }
+
+    drop(batches);
+    writer.await.expect("Writer task panicked");
+
+    if shutdown.load(Ordering::SeqCst) {
+        info!(flushed_transfers, "Flushed pending batches before shutdown");
+    }
+
+    let (blocks, elapsed) = (current_block - first_block, started.elapsed());
+
+    let mut contract_transfers: Vec<(String, u64)> = contract_transfers.into_iter().collect();
+    contract_transfers.sort_by(|(_, a), (_, b)| b.cmp(a));
+
+    for (contract, transfers) in &contract_transfers {
+        let name = map.get(&contract.to_lowercase()).map(|contract| contract.name.as_str()).unwrap_or_default();
+        info!(target: SUMMARY_TARGET, contract = %contract, name, transfers, "Transfers of contract");
+    }
+
+    info!(
+        target: SUMMARY_TARGET,
+        first_block,
+        blocks,
+        total_transfers,
+        contracts = contract_transfers.len(),
+        duration_secs = elapsed.as_secs(),
+        // Rounded to one decimal, like the blocks per second of progress logs.
+        blocks_per_second = (blocks as f64 / elapsed.as_secs_f64().max(f64::EPSILON) * 10.0).round() / 10.0,
+        rpc_errors = METRICS.rpc_errors.get(),
+        zero_value_transfers_skipped = METRICS.zero_value_transfers_skipped.get(),
+        "Finished indexing"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_while_chain_head_is_below_safety_margin() {
+        assert_eq!(confirmed_stop_block(20, 50, 0), None);
+        assert_eq!(confirmed_stop_block(50, 50, 0), None);
+        assert_eq!(confirmed_stop_block(100, 50, 51), None);
+        assert_eq!(confirmed_stop_block(100, 50, 50), Some(50));
+        assert_eq!(confirmed_stop_block(100, 0, 0), Some(100));
+    }
 }
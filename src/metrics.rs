@@ -0,0 +1,78 @@
+use std::sync::LazyLock;
+use axum::routing::get;
+use axum::Router;
+use prometheus::{Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    pub transfers_indexed: IntCounter,
+    pub blocks_processed: IntCounter,
+    pub current_block: IntGauge,
+    pub chain_head_block: IntGauge,
+    pub lag_warnings: IntCounter,
+    pub zero_value_transfers_skipped: IntCounter,
+    pub batch_insert_duration: Histogram,
+    pub rpc_errors: IntCounter,
+    pub rpc_requests: IntCounterVec,
+    pub rpc_request_errors: IntCounterVec,
+    pub rpc_request_duration: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new();
+
+        let metrics = Metrics {
+            transfers_indexed: IntCounter::new("transfers_indexed_total", "Transfers persisted to the database").unwrap(),
+            blocks_processed: IntCounter::new("blocks_processed_total", "Blocks scanned for events").unwrap(),
+            current_block: IntGauge::new("current_block", "Next block to be processed").unwrap(),
+            chain_head_block: IntGauge::new("chain_head_block", "Latest block reported by the node").unwrap(),
+            lag_warnings: IntCounter::new("lag_warnings_total", "Chain head checks that found the indexer past --lag-warning-threshold").unwrap(),
+            zero_value_transfers_skipped: IntCounter::new("zero_value_transfers_skipped_total", "Transfers dropped for a value of zero").unwrap(),
+            batch_insert_duration: Histogram::with_opts(HistogramOpts::new("batch_insert_duration_seconds", "Time spent persisting a batch")).unwrap(),
+            rpc_errors: IntCounter::new("rpc_errors_total", "Failed RPC calls").unwrap(),
+            rpc_requests: IntCounterVec::new(Opts::new("rpc_requests_total", "RPC requests per endpoint"), &["url"]).unwrap(),
+            rpc_request_errors: IntCounterVec::new(Opts::new("rpc_request_errors_total", "Failed RPC requests and connections per endpoint"), &["url"]).unwrap(),
+            rpc_request_duration: HistogramVec::new(HistogramOpts::new("rpc_request_duration_seconds", "RPC request latency per endpoint"), &["url"]).unwrap(),
+            registry,
+        };
+
+        metrics.registry.register(Box::new(metrics.transfers_indexed.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.blocks_processed.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.current_block.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.chain_head_block.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.lag_warnings.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.zero_value_transfers_skipped.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.batch_insert_duration.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.rpc_errors.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.rpc_requests.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.rpc_request_errors.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.rpc_request_duration.clone())).unwrap();
+
+        metrics
+    }
+
+    pub fn render(&self) -> String {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("Failed to encode metrics");
+
+        String::from_utf8(buffer).expect("Metrics are not valid UTF-8")
+    }
+}
+
+/// Serves the Prometheus metrics on `/metrics` until the process exits.
+pub async fn serve(port: u16) {
+    let app = Router::new().route("/metrics", get(|| async { METRICS.render() }));
+
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port))
+        .await
+        .unwrap_or_else(|e| panic!("Failed to bind metrics server to port {}: {}", port, e));
+
+    axum::serve(listener, app)
+        .await
+        .unwrap_or_else(|e| panic!("Metrics server failed: {}", e));
+}
@@ -0,0 +1,439 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::str::FromStr;
+use bigdecimal::BigDecimal;
+use clap::ValueEnum;
+use mongodb::bson::{Bson, Decimal128};
+use serde::{Deserialize, Deserializer, Serialize};
+use web3::signing::keccak256;
+use web3::types::H256;
+
+pub const ZERO_ADDRESS: &str = "0x0000000000000000000000000000000000000000";
+
+/// Version of the `Transfer` layout written by this indexer, bumped whenever stored fields change meaning.
+/// Transfers stored before versions were introduced read as version 0.
+pub const TRANSFER_SCHEMA_VERSION: u8 = 1;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Transfer {
+    /// Deterministic id from `transfer_id`, stored as the MongoDB `_id` so re-inserting a transfer is a duplicate key.
+    #[serde(rename = "_id", default, deserialize_with = "deserialize_id")]
+    pub id: String,
+    #[serde(default)]
+    pub schema_version: u8,
+    /// Only set with `--chain-id`, so records of several chains can share a database.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chain_id: Option<u64>,
+    pub contract: String,
+    /// Name of the decoded event, so records are self-describing in collections holding several event types.
+    #[serde(default = "transfer_event_name")]
+    pub event: String,
+    pub from: String,
+    pub to: String,
+    pub value: String,
+    /// Stored as a Decimal128 instead with `--mongo-decimal128`.
+    #[serde(deserialize_with = "deserialize_amount")]
+    pub value_decimal: String,
+    /// Block timestamp in the unit of `--timestamp-unit`, milliseconds unless configured otherwise.
+    pub timestamp: u64,
+    pub block_number: u64,
+    pub block_hash: String,
+    pub transaction_hash: String,
+    /// Sender of the transaction, which differs from `from` when a contract moves tokens on someone's behalf.
+    #[serde(default)]
+    pub tx_from: String,
+    pub log_index: u64,
+    #[serde(default)]
+    pub transfer_kind: TransferKind,
+    /// Gas used by the transaction, only stored with `--record-gas`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gas_used: Option<u64>,
+    /// Price paid per gas in wei, only stored with `--record-gas`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effective_gas_price: Option<u64>,
+    /// Number of other logs emitted by the transaction of any contract, e.g. the swaps and transfers around it in a router
+    /// call. Only stored with `--flatten-receipts`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sibling_log_count: Option<u64>,
+    /// Hex encoded log topics, only stored with `--keep-raw` so the transfer can be reparsed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_topics: Option<Vec<String>>,
+    /// Hex encoded log data, only stored with `--keep-raw`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_data: Option<String>,
+}
+
+/// Hashes the position of a transfer log with keccak256 into a 0x-prefixed hex id that is the same for every
+/// indexer and store. A missing chain id hashes as 0.
+pub fn transfer_id(chain_id: Option<u64>, block_number: u64, transaction_hash: &H256, log_index: u64) -> String {
+    let mut bytes = Vec::with_capacity(56);
+    bytes.extend_from_slice(&chain_id.unwrap_or_default().to_be_bytes());
+    bytes.extend_from_slice(&block_number.to_be_bytes());
+    bytes.extend_from_slice(transaction_hash.as_bytes());
+    bytes.extend_from_slice(&log_index.to_be_bytes());
+
+    format!("0x{}", hex::encode(keccak256(&bytes)))
+}
+
+/// Records stored before event names were recorded are all of their collection's event.
+fn transfer_event_name() -> String {
+    "Transfer".to_string()
+}
+
+fn approval_event_name() -> String {
+    "Approval".to_string()
+}
+
+/// Transfers stored before ids were computed carry a generated ObjectId instead.
+fn deserialize_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(match Bson::deserialize(deserializer)? {
+        Bson::String(id) => id,
+        Bson::ObjectId(id) => id.to_hex(),
+        id => id.to_string(),
+    })
+}
+
+/// Amounts are strings unless stored as a Decimal128 with `--mongo-decimal128`.
+fn deserialize_amount<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(match Bson::deserialize(deserializer)? {
+        Bson::String(amount) => amount,
+        Bson::Decimal128(amount) => amount.to_string(),
+        amount => amount.to_string(),
+    })
+}
+
+/// Significant digits a Decimal128 holds.
+const DECIMAL128_DIGITS: u64 = 34;
+
+/// Converts a normalized amount to a Decimal128, rounding amounts with more than 34 significant digits. Returns `None`
+/// when the amount can't be represented at all.
+pub fn decimal128(amount: &str) -> Option<Decimal128> {
+    let amount = BigDecimal::from_str(amount).ok()?;
+
+    let amount = match amount.digits() > DECIMAL128_DIGITS {
+        true => amount.with_prec(DECIMAL128_DIGITS).normalized(),
+        false => amount,
+    };
+
+    amount.to_string().parse().ok()
+}
+
+/// Whether a transfer created, destroyed or moved tokens, judged by the zero address on either side.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferKind {
+    Mint,
+    Burn,
+    #[default]
+    Transfer,
+}
+
+impl TransferKind {
+    /// Classifies a transfer between two formatted addresses. A transfer from and to the zero address counts as a mint.
+    pub fn of(from: &str, to: &str) -> TransferKind {
+        if from == ZERO_ADDRESS {
+            TransferKind::Mint
+        } else if to == ZERO_ADDRESS {
+            TransferKind::Burn
+        } else {
+            TransferKind::Transfer
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransferKind::Mint => "mint",
+            TransferKind::Burn => "burn",
+            TransferKind::Transfer => "transfer",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Approval {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chain_id: Option<u64>,
+    pub contract: String,
+    #[serde(default = "approval_event_name")]
+    pub event: String,
+    pub owner: String,
+    pub spender: String,
+    pub value: String,
+    pub value_decimal: String,
+    pub timestamp: u64,
+    pub block_number: u64,
+    pub block_hash: String,
+    pub transaction_hash: String,
+    pub log_index: u64
+}
+
+/// An event decoded from an `--abi` file, with its parameters formatted by name.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GenericEvent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chain_id: Option<u64>,
+    pub contract: String,
+    pub event: String,
+    pub params: BTreeMap<String, String>,
+    pub timestamp: u64,
+    pub block_number: u64,
+    pub block_hash: String,
+    pub transaction_hash: String,
+    pub log_index: u64
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BlockStats {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chain_id: Option<u64>,
+    pub block_number: u64,
+    pub timestamp: u64,
+    pub transfer_count: u64
+}
+
+/// Transfers removed because their block was replaced in a reorg, recorded for auditing.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Reorg {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chain_id: Option<u64>,
+    pub block_number: u64,
+    pub old_hash: String,
+    pub new_hash: String,
+    pub transfers_removed: u64,
+    /// Unix timestamp of the detection in seconds.
+    pub timestamp: u64,
+}
+
+/// The first and last block an address sent or received a transfer in, and how many it took part in.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct AddressActivity {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chain_id: Option<u64>,
+    pub address: String,
+    pub first_block: u64,
+    pub last_block: u64,
+    pub transfer_count: u64
+}
+
+/// Aggregates the activity of every sender and recipient of `transfers`, counting a transfer to oneself once.
+pub fn address_activity(chain_id: Option<u64>, transfers: &[Transfer]) -> Vec<AddressActivity> {
+    let mut activity: HashMap<&str, AddressActivity> = HashMap::new();
+
+    for transfer in transfers {
+        let addresses = [transfer.from.as_str(), transfer.to.as_str()];
+        let addresses = match transfer.from == transfer.to {
+            true => &addresses[..1],
+            false => &addresses[..],
+        };
+
+        for address in addresses {
+            let entry = activity.entry(address).or_insert_with(|| AddressActivity {
+                chain_id,
+                address: address.to_string(),
+                first_block: transfer.block_number,
+                last_block: transfer.block_number,
+                transfer_count: 0,
+            });
+
+            entry.first_block = entry.first_block.min(transfer.block_number);
+            entry.last_block = entry.last_block.max(transfer.block_number);
+            entry.transfer_count += 1;
+        }
+    }
+
+    activity.into_values().collect()
+}
+
+/// Unit of the timestamps stored on records. Block headers count seconds.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum TimestampUnit {
+    /// Seconds, as in block headers
+    #[value(name = "s")]
+    Seconds,
+    /// Milliseconds, the block timestamp multiplied by 1000
+    #[default]
+    #[value(name = "ms")]
+    Milliseconds,
+}
+
+impl TimestampUnit {
+    /// Converts a block timestamp in seconds to this unit.
+    pub fn convert(&self, seconds: u64) -> u64 {
+        match self {
+            TimestampUnit::Seconds => seconds,
+            TimestampUnit::Milliseconds => seconds * 1000,
+        }
+    }
+}
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// Amount of a contract transferred on one UTC day, `day` being the timestamp of its midnight in the unit of the
+/// transfer timestamps.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct DailyVolume {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chain_id: Option<u64>,
+    pub contract: String,
+    pub day: u64,
+    /// Sum of the normalized values.
+    pub volume: String,
+    pub transfer_count: u64
+}
+
+/// Sums the normalized values of `transfers`, whose timestamps are in `unit`, per contract and day.
+pub fn daily_volume<'a>(chain_id: Option<u64>, unit: TimestampUnit, transfers: impl IntoIterator<Item = &'a Transfer>) -> Vec<DailyVolume> {
+    let day_length = unit.convert(SECONDS_PER_DAY);
+    let mut volume: HashMap<(&str, u64), (BigDecimal, u64)> = HashMap::new();
+
+    for transfer in transfers {
+        let value = BigDecimal::from_str(&transfer.value_decimal).expect("Normalized value is not a decimal");
+        let day = transfer.timestamp - transfer.timestamp % day_length;
+
+        let entry = volume.entry((transfer.contract.as_str(), day)).or_default();
+        entry.0 += value;
+        entry.1 += 1;
+    }
+
+    volume
+        .into_iter()
+        .map(|((contract, day), (volume, transfer_count))| DailyVolume {
+            chain_id,
+            contract: contract.to_string(),
+            day,
+            volume: volume.to_plain_string(),
+            transfer_count,
+        })
+        .collect()
+}
+
+/// Drops transfers sharing a `(transaction_hash, log_index)` with an earlier one, returning how many were dropped.
+pub fn dedup_transfers(transfers: &mut Vec<Transfer>) -> usize {
+    let before = transfers.len();
+    let mut seen = HashSet::new();
+
+    transfers.retain(|transfer| seen.insert((transfer.transaction_hash.clone(), transfer.log_index)));
+
+    before - transfers.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer(from: &str, to: &str, block_number: u64) -> Transfer {
+        Transfer {
+            id: String::new(),
+            schema_version: TRANSFER_SCHEMA_VERSION,
+            chain_id: None,
+            contract: "0xc99a6a985ed2cac1ef41640596c5a5f9f4e19ef5".to_string(),
+            event: "Transfer".to_string(),
+            from: from.to_string(),
+            to: to.to_string(),
+            value: "1".to_string(),
+            value_decimal: "1".to_string(),
+            timestamp: 0,
+            block_number,
+            block_hash: String::new(),
+            transaction_hash: String::new(),
+            tx_from: String::new(),
+            log_index: 0,
+            transfer_kind: TransferKind::of(from, to),
+            gas_used: None,
+            effective_gas_price: None,
+            sibling_log_count: None,
+            raw_topics: None,
+            raw_data: None,
+        }
+    }
+
+    #[test]
+    fn aggregates_address_activity() {
+        let transfers = [transfer("0xa", "0xb", 12), transfer("0xb", "0xc", 10), transfer("0xa", "0xa", 15)];
+
+        let mut activity = address_activity(Some(2020), &transfers);
+        activity.sort_by(|a, b| a.address.cmp(&b.address));
+
+        let summary: Vec<(&str, u64, u64, u64)> = activity.iter()
+            .map(|a| (a.address.as_str(), a.first_block, a.last_block, a.transfer_count))
+            .collect();
+
+        assert_eq!(summary, [("0xa", 12, 15, 2), ("0xb", 10, 12, 2), ("0xc", 10, 10, 1)]);
+        assert!(activity.iter().all(|a| a.chain_id == Some(2020)));
+    }
+
+    #[test]
+    fn sums_daily_volume_per_contract() {
+        let mut transfers = [transfer("0xa", "0xb", 1), transfer("0xb", "0xc", 2), transfer("0xc", "0xa", 3)];
+        transfers[0].value_decimal = "1.5".to_string();
+        transfers[1].value_decimal = "0.25".to_string();
+        transfers[1].timestamp = 86_399_999;
+        transfers[2].timestamp = 86_400_000;
+
+        let volume = daily_volume(None, TimestampUnit::Milliseconds, &transfers);
+        let mut summary: Vec<(u64, &str, u64)> = volume.iter().map(|v| (v.day, v.volume.as_str(), v.transfer_count)).collect();
+        summary.sort();
+
+        assert_eq!(summary, [(0, "1.75", 2), (86_400_000, "1", 1)]);
+
+        transfers[1].timestamp = 86_399;
+        transfers[2].timestamp = 86_400;
+
+        let volume = daily_volume(None, TimestampUnit::Seconds, &transfers);
+        let mut summary: Vec<(u64, &str, u64)> = volume.iter().map(|v| (v.day, v.volume.as_str(), v.transfer_count)).collect();
+        summary.sort();
+
+        assert_eq!(summary, [(0, "1.75", 2), (86_400, "1", 1)]);
+    }
+
+    #[test]
+    fn transfer_ids_are_stable() {
+        let transaction_hash: H256 = "0x0000000000000000000000000000000000000000000000000000000000abc000".parse().unwrap();
+
+        let id = transfer_id(Some(2020), 21_000_000, &transaction_hash, 1);
+        assert_eq!(id, transfer_id(Some(2020), 21_000_000, &transaction_hash, 1));
+        assert_eq!(id, "0x1905e6c06cc2b5b51de2880c482a9d9f73ccfeb63a2d47a46255f86ffc5c6030");
+
+        assert_ne!(id, transfer_id(Some(2020), 21_000_000, &transaction_hash, 2));
+        assert_ne!(id, transfer_id(Some(1), 21_000_000, &transaction_hash, 1));
+        assert_ne!(id, transfer_id(None, 21_000_000, &transaction_hash, 1));
+    }
+
+    #[test]
+    fn reads_object_ids_of_older_transfers() {
+        let id = mongodb::bson::oid::ObjectId::new();
+        let mut document = mongodb::bson::to_document(&transfer("0xa", "0xb", 1)).unwrap();
+        document.insert("_id", id);
+
+        let transfer: Transfer = mongodb::bson::from_document(document).unwrap();
+        assert_eq!(transfer.id, id.to_hex());
+    }
+
+    #[test]
+    fn reads_unversioned_transfers() {
+        let mut document = mongodb::bson::to_document(&transfer("0xa", "0xb", 1)).unwrap();
+        assert_eq!(document.get_i32("schema_version").unwrap(), TRANSFER_SCHEMA_VERSION as i32);
+
+        document.remove("schema_version");
+        document.remove("event");
+
+        let transfer: Transfer = mongodb::bson::from_document(document).unwrap();
+        assert_eq!(transfer.schema_version, 0);
+        assert_eq!(transfer.event, "Transfer");
+    }
+
+    #[test]
+    fn converts_amounts_to_decimal128() {
+        assert_eq!(decimal128("1.500000000000000000").unwrap().to_string(), "1.500000000000000000");
+        assert_eq!(decimal128("0.000000000000000000").unwrap().to_string(), "0");
+
+        // The largest uint256 with 18 decimals has 78 significant digits, rounded to 34.
+        let max = "115792089237316195423570985008687907853269984665640564039457.584007913129639935";
+        assert_eq!(decimal128(max).unwrap().to_string(), "1.157920892373161954235709850086879E+59");
+
+        let mut document = mongodb::bson::to_document(&transfer("0xa", "0xb", 1)).unwrap();
+        document.insert("value_decimal", decimal128("1.5").unwrap());
+
+        let transfer: Transfer = mongodb::bson::from_document(document).unwrap();
+        assert_eq!(transfer.value_decimal, "1.5");
+    }
+}
@@ -0,0 +1,647 @@
+use std::collections::{HashMap, HashSet};
+use tracing::warn;
+use web3::ethabi::{Event, RawLog};
+use web3::types::{Block, Log, TransactionReceipt, H160, H256, U256};
+use crate::contracts::Contract;
+use crate::contracts::ContractType::{ERC20, ERC721};
+use crate::events::{approval_event, format_address, format_token, transfer_event, AddressCase, ERC_APPROVAL_TOPIC, ERC_TRANSFER_TOPIC};
+use crate::metrics::METRICS;
+use crate::models::{transfer_id, Approval, GenericEvent, TimestampUnit, TRANSFER_SCHEMA_VERSION, Transfer, TransferKind};
+use crate::{normalize_address, to_string};
+
+/// Block fields copied onto every record parsed from its logs.
+pub struct BlockMeta {
+    pub number: u64,
+    pub hash: String,
+    pub timestamp: u64,
+}
+
+/// A log together with the fields of its transaction that end up on records.
+pub struct TxLog {
+    pub tx_from: Option<H160>,
+    pub gas_used: Option<u64>,
+    pub effective_gas_price: Option<u64>,
+    /// Number of other logs in the transaction's receipt, whether they match or not.
+    pub sibling_log_count: Option<u64>,
+    pub log: Log,
+}
+
+/// The matching logs of one block in chain order. `block` is `None` when the block was never fetched
+/// because it has no matching logs.
+pub struct BlockLogs {
+    pub number: u64,
+    pub block: Option<BlockMeta>,
+    pub logs: Vec<TxLog>,
+}
+
+/// Records parsed from logs, waiting to be written in the next batch.
+#[derive(Default)]
+pub struct Records {
+    pub transfers: Vec<Transfer>,
+    pub approvals: Vec<Approval>,
+    pub events: Vec<GenericEvent>,
+}
+
+impl Records {
+    /// Moves the records of `other` behind these.
+    pub fn append(&mut self, mut other: Records) {
+        self.transfers.append(&mut other.transfers);
+        self.approvals.append(&mut other.approvals);
+        self.events.append(&mut other.events);
+    }
+}
+
+/// The fields of a Transfer log, with addresses in lowercase.
+pub struct DecodedTransfer<'a> {
+    pub event: &'a Event,
+    pub from: String,
+    pub to: String,
+    pub value: U256,
+}
+
+/// Decodes the logs of watched contracts into records.
+pub struct LogParser<'a> {
+    /// Keyed by parsed address so matching a log neither formats nor allocates.
+    contracts: HashMap<H160, Contract>,
+    abi_events: &'a HashMap<H256, Event>,
+    watched_addresses: &'a HashSet<String>,
+    /// Topics of the ABI events and the standard Transfer and Approval events, `topics` adds those of non-standard
+    /// Transfer events of the watched contracts.
+    event_topics: Vec<H256>,
+    topics: Vec<H256>,
+    index_transfers: bool,
+    approval_topic: H256,
+    chain_id: Option<u64>,
+    keep_raw: bool,
+    record_gas: bool,
+    record_sibling_logs: bool,
+    skip_zero_value: bool,
+    address_case: AddressCase,
+    timestamp_unit: TimestampUnit,
+    erc20_event: Event,
+    erc721_event: Event,
+    erc20_approval_event: Event,
+    erc721_approval_event: Event,
+}
+
+impl<'a> LogParser<'a> {
+    pub fn new(
+        contracts: &HashMap<String, Contract>,
+        abi_events: &'a HashMap<H256, Event>,
+        watched_addresses: &'a HashSet<String>,
+        index_transfers: bool,
+        index_approvals: bool,
+        chain_id: Option<u64>,
+        keep_raw: bool,
+    ) -> LogParser<'a> {
+        let approval_topic: H256 = ERC_APPROVAL_TOPIC.parse().expect("Invalid approval topic");
+
+        let mut topics: Vec<H256> = abi_events.keys().copied().collect();
+        if index_transfers {
+            topics.push(ERC_TRANSFER_TOPIC.parse().expect("Invalid transfer topic"));
+        }
+        if index_approvals {
+            topics.push(approval_topic);
+        }
+
+        let mut parser = LogParser {
+            contracts: by_address(contracts),
+            abi_events,
+            watched_addresses,
+            event_topics: topics,
+            topics: vec![],
+            index_transfers,
+            approval_topic,
+            chain_id,
+            keep_raw,
+            record_gas: false,
+            record_sibling_logs: false,
+            skip_zero_value: false,
+            address_case: AddressCase::default(),
+            timestamp_unit: TimestampUnit::default(),
+            erc20_event: transfer_event(&ERC20),
+            erc721_event: transfer_event(&ERC721),
+            erc20_approval_event: approval_event(&ERC20),
+            erc721_approval_event: approval_event(&ERC721),
+        };
+
+        parser.update_topics();
+        parser
+    }
+
+    /// Formats the addresses of records in `address_case`. Watched addresses still match in any case.
+    pub fn with_address_case(mut self, address_case: AddressCase) -> LogParser<'a> {
+        self.address_case = address_case;
+        self
+    }
+
+    /// Stores the timestamps of records in `timestamp_unit`.
+    pub fn with_timestamp_unit(mut self, timestamp_unit: TimestampUnit) -> LogParser<'a> {
+        self.timestamp_unit = timestamp_unit;
+        self
+    }
+
+    /// Stores the gas used and effective gas price of the transaction on transfers.
+    pub fn with_gas(mut self, record_gas: bool) -> LogParser<'a> {
+        self.record_gas = record_gas;
+        self
+    }
+
+    pub fn records_gas(&self) -> bool {
+        self.record_gas
+    }
+
+    /// Stores the number of other logs of the transaction on transfers.
+    pub fn with_sibling_logs(mut self, record_sibling_logs: bool) -> LogParser<'a> {
+        self.record_sibling_logs = record_sibling_logs;
+        self
+    }
+
+    /// Drops transfers with a value of zero, of every contract.
+    pub fn with_skip_zero_value(mut self, skip_zero_value: bool) -> LogParser<'a> {
+        self.skip_zero_value = skip_zero_value;
+        self
+    }
+
+    /// Replaces the watched contracts, e.g. after reloading them from the database.
+    pub fn set_contracts(&mut self, contracts: &HashMap<String, Contract>) {
+        self.contracts = by_address(contracts);
+        self.update_topics();
+    }
+
+    fn update_topics(&mut self) {
+        self.topics = self.event_topics.clone();
+
+        if self.index_transfers {
+            for event in self.contracts.values().filter_map(|contract| contract.transfer_abi.as_ref()) {
+                let topic = event.signature();
+
+                if !self.topics.contains(&topic) {
+                    self.topics.push(topic);
+                }
+            }
+        }
+    }
+
+    /// The topic0 hashes of every indexed event.
+    pub fn topics(&self) -> &[H256] {
+        &self.topics
+    }
+
+    /// The addresses of the watched contracts.
+    pub fn addresses(&self) -> Vec<H160> {
+        self.contracts.keys().copied().collect()
+    }
+
+    /// Whether the log is an indexed event emitted by a watched contract.
+    pub fn matches(&self, log: &Log) -> bool {
+        log.topics.first().is_some_and(|topic| self.topics.contains(topic)) && self.contracts.contains_key(&log.address)
+    }
+
+    /// Collects the matching logs of a block from the receipts of its transactions, ordered as they appear on chain.
+    /// Receipts carry the transaction sender, so the block is only needed for its header.
+    pub fn block_logs<T>(&self, block: &Block<T>, receipts: Vec<TransactionReceipt>) -> BlockLogs {
+        let mut logs = receipts
+            .into_iter()
+            .flat_map(|receipt| {
+                let tx_from = Some(receipt.from);
+                let gas_used = receipt.gas_used.and_then(|gas| u64::try_from(gas).ok());
+                let effective_gas_price = receipt.effective_gas_price.and_then(|price| u64::try_from(price).ok());
+                let sibling_log_count = Some(receipt.logs.len().saturating_sub(1) as u64);
+
+                receipt.logs
+                    .into_iter()
+                    .filter(|log| self.matches(log))
+                    .map(move |log| TxLog { tx_from, gas_used, effective_gas_price, sibling_log_count, log })
+            })
+            .collect::<Vec<TxLog>>();
+
+        logs.sort_by_key(|tx_log| (tx_log.log.transaction_index, tx_log.log.log_index));
+
+        let block = self.block_meta(block);
+
+        BlockLogs {
+            number: block.number,
+            block: Some(block),
+            logs,
+        }
+    }
+
+    /// The fields of `block` copied onto its records, with the timestamp in the configured unit.
+    pub fn block_meta<T>(&self, block: &Block<T>) -> BlockMeta {
+        BlockMeta {
+            number: block.number.expect("Fetched block has no number").as_u64(),
+            hash: block.hash.map(|h| to_string(&h)).unwrap_or_default(),
+            timestamp: self.timestamp_unit.convert(block.timestamp.as_u64()),
+        }
+    }
+
+    /// Decodes every log of a fetched block, appending the resulting records to `records`.
+    pub fn parse_block(&self, block_logs: &BlockLogs, records: &mut Records) {
+        let Some(block) = &block_logs.block else {
+            return;
+        };
+
+        for tx_log in &block_logs.logs {
+            self.parse(block, tx_log, records);
+        }
+    }
+
+    /// The watched, enabled contract at `address`.
+    pub fn contract(&self, address: &H160) -> Option<&Contract> {
+        self.contracts.get(address)
+    }
+
+    /// Decodes a Transfer log of `contract` with its `transfer_abi` when the event topic matches it, or else the
+    /// standard event of its type. Warns and returns `None` when the log doesn't fit. `reparse` decodes stored raw logs
+    /// this way too.
+    pub fn decode_transfer<'s>(&'s self, contract: &'s Contract, topics: &[H256], data: &[u8], transaction_hash: &str, log_index: u64) -> Option<DecodedTransfer<'s>> {
+        let event = match (&contract.transfer_abi, &contract.erc) {
+            (Some(event), _) if topics.first() == Some(&event.signature()) => event,
+            (_, ERC20) => &self.erc20_event,
+            (_, ERC721) => &self.erc721_event,
+        };
+
+        let data = decode(event, topics, data, transaction_hash, log_index)?;
+
+        // The first two addresses and the first uint, which is where the standard events have them too.
+        let mut addresses = data.params.iter().filter_map(|param| format_address(&param.value));
+        let (Some(from), Some(to)) = (addresses.next(), addresses.next()) else {
+            warn!(transaction = %transaction_hash, log_index, "Skipping transfer without a sender and recipient");
+            return None;
+        };
+
+        let Some(value) = data.params.iter().find_map(|param| param.value.clone().into_uint()) else {
+            warn!(transaction = %transaction_hash, log_index, "Skipping transfer with a malformed value");
+            return None;
+        };
+
+        Some(DecodedTransfer { event, from, to, value })
+    }
+
+    /// Decodes a matching log, appending the resulting record to `records`.
+    pub fn parse(&self, block: &BlockMeta, tx_log: &TxLog, records: &mut Records) {
+        let TxLog { tx_from, gas_used, effective_gas_price, sibling_log_count, log } = tx_log;
+
+        let (Some(topic), Some(contract)) = (log.topics.first(), self.contracts.get(&log.address)) else {
+            return;
+        };
+        let transaction_hash = to_string(&log.transaction_hash.unwrap_or_default());
+        let log_index = log.log_index.map(|i| i.as_u64()).unwrap_or_default();
+
+        if let Some(event) = self.abi_events.get(topic) {
+            let Some(data) = decode(event, &log.topics, &log.data.0, &transaction_hash, log_index) else {
+                return;
+            };
+
+            records.events.push(GenericEvent {
+                chain_id: self.chain_id,
+                contract: self.address_case.apply(contract.address.clone()),
+                event: event.name.clone(),
+                params: data.params.iter().map(|param| (param.name.clone(), format_token(&param.value))).collect(),
+                timestamp: block.timestamp,
+                block_number: block.number,
+                block_hash: block.hash.clone(),
+                transaction_hash,
+                log_index
+            });
+
+            return;
+        }
+
+        if *topic == self.approval_topic {
+            let event = match contract.erc {
+                ERC20 => &self.erc20_approval_event,
+                ERC721 => &self.erc721_approval_event,
+            };
+
+            let Some(data) = decode(event, &log.topics, &log.data.0, &transaction_hash, log_index) else {
+                return;
+            };
+
+            let Some(value) = data.params[2].value.clone().into_uint() else {
+                warn!(transaction = %transaction_hash, log_index, "Skipping approval with a malformed value");
+                return;
+            };
+
+            records.approvals.push(Approval {
+                chain_id: self.chain_id,
+                contract: self.address_case.apply(contract.address.clone()),
+                event: event.name.clone(),
+                owner: self.address_case.apply(format_address(&data.params[0].value).expect("Approval owner is not an address")),
+                spender: self.address_case.apply(format_address(&data.params[1].value).expect("Approval spender is not an address")),
+                value: value.to_string(),
+                value_decimal: contract.normalize_value(value),
+                timestamp: block.timestamp,
+                block_number: block.number,
+                block_hash: block.hash.clone(),
+                transaction_hash,
+                log_index
+            });
+
+            return;
+        }
+
+        let filter = contract.transfer_filter.as_ref();
+        if filter.and_then(|filter| filter.topics).is_some_and(|topics| topics != log.topics.len()) {
+            return;
+        }
+
+        let Some(DecodedTransfer { event, from, to, value }) = self.decode_transfer(contract, &log.topics, &log.data.0, &transaction_hash, log_index) else {
+            return;
+        };
+
+        if !self.watched_addresses.is_empty()
+            && !self.watched_addresses.contains(&normalize_address(&from))
+            && !self.watched_addresses.contains(&normalize_address(&to)) {
+            return;
+        }
+
+        if value.is_zero() && (self.skip_zero_value || filter.is_some_and(|filter| filter.exclude_zero_value)) {
+            METRICS.zero_value_transfers_skipped.inc();
+            return;
+        }
+
+        let value_decimal = contract.normalize_value(value);
+        let value = value.to_string();
+
+        records.transfers.push(Transfer {
+            id: transfer_id(self.chain_id, block.number, &log.transaction_hash.unwrap_or_default(), log_index),
+            schema_version: TRANSFER_SCHEMA_VERSION,
+            chain_id: self.chain_id,
+            contract: self.address_case.apply(contract.address.clone()),
+            event: event.name.clone(),
+            transfer_kind: TransferKind::of(&from, &to),
+            from: self.address_case.apply(from),
+            to: self.address_case.apply(to),
+            value,
+            value_decimal,
+            timestamp: block.timestamp,
+            block_number: block.number,
+            block_hash: block.hash.clone(),
+            transaction_hash,
+            tx_from: tx_from.map(|from| self.address_case.apply(format!("{:#x}", from))).unwrap_or_default(),
+            log_index,
+            gas_used: gas_used.filter(|_| self.record_gas),
+            effective_gas_price: effective_gas_price.filter(|_| self.record_gas),
+            sibling_log_count: sibling_log_count.filter(|_| self.record_sibling_logs),
+            raw_topics: self.keep_raw.then(|| log.topics.iter().map(|topic| format!("{:#x}", topic)).collect()),
+            raw_data: self.keep_raw.then(|| to_string(&log.data)),
+        });
+    }
+}
+
+/// Decodes `log` as `event`, or warns and returns `None` when the log doesn't fit it, e.g. the ERC721 Transfer of a
+/// contract listed as ERC20, which indexes one more topic, or a non-standard event sharing the topic.
+fn decode(event: &Event, topics: &[H256], data: &[u8], transaction_hash: &str, log_index: u64) -> Option<web3::ethabi::Log> {
+    let expected_topics = event.inputs.iter().filter(|input| input.indexed).count() + 1;
+    if topics.len() != expected_topics {
+        warn!(transaction = %transaction_hash, log_index, event = %event.name, topics = topics.len(), expected_topics, "Skipping log with an unexpected number of topics");
+        return None;
+    }
+
+    match event.parse_log(RawLog { topics: topics.to_vec(), data: data.to_vec() }) {
+        Ok(data) => Some(data),
+        Err(e) => {
+            warn!(transaction = %transaction_hash, log_index, event = %event.name, error = %e, "Skipping undecodable log");
+            None
+        }
+    }
+}
+
+fn by_address(contracts: &HashMap<String, Contract>) -> HashMap<H160, Contract> {
+    contracts
+        .values()
+        .filter(|contract| contract.enabled)
+        .map(|contract| (contract.address.parse().expect("Contract address was validated"), contract.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use web3::types::Transaction;
+    use crate::contracts::{default_contracts, TransferFilter};
+    use crate::models::ZERO_ADDRESS;
+
+    const ALICE: &str = "0x1a2b3c4d5e6f708192a3b4c5d6e7f8091a2b3c4d";
+    const BOB: &str = "0x5e6f708192a3b4c5d6e7f8091a2b3c4d5e6f7081";
+
+    /// Parser options the tests vary, everything else is fixed.
+    #[derive(Default)]
+    struct Options {
+        index_approvals: bool,
+        skip_zero_value: bool,
+    }
+
+    /// The fixture block, a WETH transfer, an SLP mint, a zero-value WETH burn and a transfer of an
+    /// unwatched contract, with its receipts in the order they were fetched.
+    fn fixture() -> (Block<Transaction>, Vec<TransactionReceipt>) {
+        let block = serde_json::from_str(include_str!("../tests/fixtures/block.json")).unwrap();
+        let receipts = serde_json::from_str(include_str!("../tests/fixtures/receipts.json")).unwrap();
+
+        (block, receipts)
+    }
+
+    /// Parses `block` and its `receipts` on chain 2020, watching `contracts`.
+    fn parse(block: &Block<Transaction>, receipts: Vec<TransactionReceipt>, contracts: &HashMap<String, Contract>, options: Options) -> Records {
+        let (abi_events, watched_addresses) = (HashMap::new(), HashSet::new());
+        let parser = LogParser::new(contracts, &abi_events, &watched_addresses, true, options.index_approvals, Some(2020), false)
+            .with_skip_zero_value(options.skip_zero_value);
+
+        let mut records = Records::default();
+        parser.parse_block(&parser.block_logs(block, receipts), &mut records);
+
+        records
+    }
+
+    /// Parses the unchanged fixture, watching `contracts`.
+    fn parse_fixture(contracts: &HashMap<String, Contract>, options: Options) -> Records {
+        let (block, receipts) = fixture();
+
+        parse(&block, receipts, contracts, options)
+    }
+
+    #[test]
+    fn parses_transfers_in_chain_order() {
+        let records = parse_fixture(&default_contracts(), Options::default());
+
+        let positions: Vec<(&str, u64)> = records.transfers.iter().map(|t| (t.transaction_hash.as_str(), t.log_index)).collect();
+        assert_eq!(positions, [
+            ("0x0000000000000000000000000000000000000000000000000000000000abc000", 1),
+            ("0x0000000000000000000000000000000000000000000000000000000000abc001", 2),
+            ("0x0000000000000000000000000000000000000000000000000000000000abc002", 3),
+        ]);
+
+        let transfer = &records.transfers[0];
+        assert_eq!(transfer.chain_id, Some(2020));
+        assert_eq!(transfer.contract, "0xc99a6a985ed2cac1ef41640596c5a5f9f4e19ef5");
+        assert_eq!(transfer.from, ALICE);
+        assert_eq!(transfer.to, BOB);
+        assert_eq!(transfer.value, "1500000000000000000");
+        assert_eq!(transfer.value_decimal, "1.500000000000000000");
+        assert_eq!(transfer.timestamp, 1_700_000_000_000);
+        assert_eq!(transfer.block_number, 21_000_000);
+        assert_eq!(transfer.block_hash, "0x6b1c2f0e4d0f8b7a9b1d3e0c5f2a7d4e8c9b0a1f2e3d4c5b6a79880716253443");
+        assert_eq!(transfer.tx_from, ALICE);
+        assert_eq!(transfer.transfer_kind, TransferKind::Transfer);
+        assert!(records.approvals.is_empty());
+    }
+
+    #[test]
+    fn attributes_transfers_to_the_emitting_contract() {
+        let (mut block, receipts) = fixture();
+
+        // The WETH transfer happens inside a call to a router, the SLP mint inside a contract creation.
+        block.transactions[0].to = Some("0x7d0556d55ca1a92708681e2e231733ebd922597d".parse().unwrap());
+        block.transactions[1].to = None;
+
+        let records = parse(&block, receipts, &default_contracts(), Options::default());
+
+        let contracts: Vec<&str> = records.transfers.iter().map(|t| t.contract.as_str()).collect();
+        assert_eq!(contracts, [
+            "0xc99a6a985ed2cac1ef41640596c5a5f9f4e19ef5",
+            "0xa8754b9fa15fc18bb59458815510e40a12cd2014",
+            "0xc99a6a985ed2cac1ef41640596c5a5f9f4e19ef5",
+        ]);
+    }
+
+    #[test]
+    fn skips_disabled_contracts() {
+        let mut contracts = default_contracts();
+        contracts.get_mut("0xc99a6a985ed2cac1ef41640596c5a5f9f4e19ef5").unwrap().enabled = false;
+
+        let records = parse_fixture(&contracts, Options::default());
+
+        let contracts: Vec<&str> = records.transfers.iter().map(|t| t.contract.as_str()).collect();
+        assert_eq!(contracts, ["0xa8754b9fa15fc18bb59458815510e40a12cd2014"]);
+    }
+
+    #[test]
+    fn applies_contract_transfer_filters() {
+        // The zero-value WETH burn is dropped, the SLP mint has 3 topics and no longer matches.
+        let mut contracts = default_contracts();
+        contracts.get_mut("0xc99a6a985ed2cac1ef41640596c5a5f9f4e19ef5").unwrap().transfer_filter = Some(TransferFilter { topics: Some(3), exclude_zero_value: true });
+        contracts.get_mut("0xa8754b9fa15fc18bb59458815510e40a12cd2014").unwrap().transfer_filter = Some(TransferFilter { topics: Some(4), exclude_zero_value: false });
+
+        let records = parse_fixture(&contracts, Options::default());
+
+        let positions: Vec<(&str, u64)> = records.transfers.iter().map(|t| (t.contract.as_str(), t.log_index)).collect();
+        assert_eq!(positions, [("0xc99a6a985ed2cac1ef41640596c5a5f9f4e19ef5", 1)]);
+    }
+
+    #[test]
+    fn parsing_the_same_log_yields_the_same_id() {
+        let contracts = default_contracts();
+        let (first, second) = (parse_fixture(&contracts, Options::default()), parse_fixture(&contracts, Options { index_approvals: true, ..Options::default() }));
+
+        let ids = |records: &Records| records.transfers.iter().map(|t| t.id.clone()).collect::<Vec<String>>();
+        assert_eq!(ids(&first), ids(&second));
+        assert_eq!(first.transfers[0].id, transfer_id(Some(2020), 21_000_000, &"0x0000000000000000000000000000000000000000000000000000000000abc000".parse().unwrap(), 1));
+        assert_eq!(ids(&first).iter().collect::<HashSet<_>>().len(), 3);
+    }
+
+    #[test]
+    fn parses_mints_burns_and_zero_values() {
+        let records = parse_fixture(&default_contracts(), Options::default());
+
+        let mint = &records.transfers[1];
+        assert_eq!(mint.contract, "0xa8754b9fa15fc18bb59458815510e40a12cd2014");
+        assert_eq!((mint.from.as_str(), mint.to.as_str()), (ZERO_ADDRESS, ALICE));
+        assert_eq!(mint.value_decimal, "100");
+        assert_eq!(mint.transfer_kind, TransferKind::Mint);
+
+        let burn = &records.transfers[2];
+        assert_eq!((burn.from.as_str(), burn.to.as_str()), (BOB, ZERO_ADDRESS));
+        assert_eq!(burn.value, "0");
+        assert_eq!(burn.value_decimal, "0.000000000000000000");
+        assert_eq!(burn.transfer_kind, TransferKind::Burn);
+    }
+
+    #[test]
+    fn parses_max_value_as_decimal() {
+        let (block, mut receipts) = fixture();
+
+        // The WETH transfer, after the approval.
+        let mut logs: Vec<&mut Log> = receipts.iter_mut().flat_map(|receipt| receipt.logs.iter_mut()).collect();
+        logs.sort_by_key(|log| log.log_index);
+        logs[1].data.0 = vec![0; 32];
+        U256::MAX.to_big_endian(&mut logs[1].data.0);
+
+        let records = parse(&block, receipts, &default_contracts(), Options::default());
+
+        let value = &records.transfers[0].value;
+        assert_eq!(value, "115792089237316195423570985008687907853269984665640564039457584007913129639935");
+        assert_eq!(U256::from_dec_str(value).unwrap(), U256::MAX);
+    }
+
+    #[test]
+    fn skips_zero_value_transfers_when_asked() {
+        let records = parse_fixture(&default_contracts(), Options { skip_zero_value: true, ..Options::default() });
+
+        let values: Vec<&str> = records.transfers.iter().map(|t| t.value.as_str()).collect();
+        assert_eq!(values, ["1500000000000000000", "100"]);
+    }
+
+    #[test]
+    fn parses_approvals_only_when_indexed() {
+        let records = parse_fixture(&default_contracts(), Options { index_approvals: true, ..Options::default() });
+
+        assert_eq!(records.transfers.len(), 3);
+        assert_eq!(records.approvals.len(), 1);
+        assert_eq!(records.approvals[0].owner, ALICE);
+        assert_eq!(records.approvals[0].spender, BOB);
+        assert_eq!(records.approvals[0].value, "2000000000000000000");
+    }
+
+    #[test]
+    fn skips_logs_with_unexpected_topics() {
+        let (block, mut receipts) = fixture();
+
+        // The approval gets an ERC721 style fourth topic, the first transfer loses its recipient, the second keeps only
+        // the event topic and the third loses even that.
+        let mut logs: Vec<&mut Log> = receipts.iter_mut().flat_map(|receipt| receipt.logs.iter_mut()).collect();
+        logs.sort_by_key(|log| log.log_index);
+        logs[0].topics.push(H256::zero());
+        logs[1].topics.truncate(2);
+        logs[2].topics.truncate(1);
+        logs[3].topics.clear();
+
+        let records = parse(&block, receipts, &default_contracts(), Options { index_approvals: true, ..Options::default() });
+
+        assert!(records.transfers.is_empty());
+        assert!(records.approvals.is_empty());
+    }
+
+    #[test]
+    fn decodes_transfers_with_a_contract_abi_override() {
+        let (block, mut receipts) = fixture();
+
+        // WETH indexes the value of its transfers instead of putting it in the data.
+        let weth = "0xc99a6a985ed2cac1ef41640596c5a5f9f4e19ef5";
+        for log in receipts.iter_mut().flat_map(|receipt| receipt.logs.iter_mut()).filter(|log| log.topics.len() == 3 && log.address == weth.parse().unwrap()) {
+            log.topics.push(H256::from_slice(&log.data.0));
+            log.data.0.clear();
+        }
+
+        let mut contracts = default_contracts();
+
+        // The standard event expects three topics, so only the SLP mint is left.
+        assert_eq!(parse(&block, receipts.clone(), &contracts, Options::default()).transfers.len(), 1);
+
+        contracts.get_mut(weth).unwrap().transfer_abi = Some(serde_json::from_str(r#"{
+            "name": "Transfer",
+            "anonymous": false,
+            "inputs": [
+                {"name": "src", "type": "address", "indexed": true},
+                {"name": "dst", "type": "address", "indexed": true},
+                {"name": "wad", "type": "uint256", "indexed": true}
+            ]
+        }"#).unwrap());
+
+        let records = parse(&block, receipts, &contracts, Options::default());
+        let values: Vec<(&str, &str)> = records.transfers.iter().map(|t| (t.contract.as_str(), t.value.as_str())).collect();
+        assert_eq!(values, [(weth, "1500000000000000000"), ("0xa8754b9fa15fc18bb59458815510e40a12cd2014", "100"), (weth, "0")]);
+        assert_eq!((records.transfers[0].from.as_str(), records.transfers[0].to.as_str()), (ALICE, BOB));
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Tracks indexing speed as a moving average over the last `window`, so it reflects the current
+/// speed rather than the speed since startup.
+pub struct BlockRate {
+    window: Duration,
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl BlockRate {
+    pub fn new(window: Duration) -> BlockRate {
+        BlockRate {
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Records that `block` was processed at `now`.
+    pub fn record(&mut self, now: Instant, block: u64) {
+        self.samples.push_back((now, block));
+
+        // Keep the newest sample that fell out of the window so the average always covers the full window.
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) >= self.window {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Blocks processed per second within the window, `None` until two samples are spaced apart.
+    pub fn blocks_per_second(&self) -> Option<f64> {
+        let ((first_at, first_block), (last_at, last_block)) = (self.samples.front()?, self.samples.back()?);
+        let elapsed = last_at.duration_since(*first_at).as_secs_f64();
+
+        (elapsed > 0.0).then(|| last_block.saturating_sub(*first_block) as f64 / elapsed)
+    }
+
+    /// Estimated time to process `remaining` blocks at the current speed.
+    pub fn eta(&self, remaining: u64) -> Option<Duration> {
+        let rate = self.blocks_per_second().filter(|rate| *rate > 0.0)?;
+
+        Some(Duration::from_secs_f64(remaining as f64 / rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn averages_over_the_window_only() {
+        let start = Instant::now();
+        let mut rate = BlockRate::new(Duration::from_secs(10));
+
+        // 100 blocks per second for the first 20 seconds, then 10 per second.
+        for second in 0..=20 {
+            rate.record(start + Duration::from_secs(second), second * 100);
+        }
+        for second in 21..=40 {
+            rate.record(start + Duration::from_secs(second), 2000 + (second - 20) * 10);
+        }
+
+        assert_eq!(rate.blocks_per_second(), Some(10.0));
+        assert_eq!(rate.eta(600), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn has_no_rate_before_time_passes() {
+        let mut rate = BlockRate::new(Duration::from_secs(10));
+        assert_eq!(rate.blocks_per_second(), None);
+
+        rate.record(Instant::now(), 5);
+        assert_eq!(rate.blocks_per_second(), None);
+        assert_eq!(rate.eta(100), None);
+    }
+}
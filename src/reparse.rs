@@ -0,0 +1,84 @@
+use std::error::Error;
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Bson};
+use mongodb::Collection;
+use tracing::warn;
+use web3::types::{H160, H256};
+use crate::events::AddressCase;
+use crate::models::{self, Transfer, TransferKind};
+use crate::parser::{DecodedTransfer, LogParser};
+use crate::sink::chain_filter;
+
+/// Re-derives from, to, value, value_decimal and kind of every transfer of `chain_id` stored with its raw log, decoding
+/// it like `parser` does without fetching blocks. Addresses are formatted in `address_case` and amounts stored as a
+/// Decimal128 with `decimal128`, which should match how they were stored. Transfers of contracts the parser doesn't
+/// watch and logs that don't decode are skipped with a warning. Returns how many transfers were reparsed and how many
+/// of those changed.
+pub async fn reparse(
+    collection: &Collection<Transfer>,
+    parser: &LogParser<'_>,
+    chain_id: Option<u64>,
+    address_case: AddressCase,
+    decimal128: bool,
+) -> Result<(u64, u64), Box<dyn Error>> {
+    let mut filter = chain_filter(chain_id);
+    filter.insert("raw_topics", doc! { "$exists": true });
+    filter.insert("raw_data", doc! { "$exists": true });
+
+    let mut cursor = collection.find(filter, None).await?;
+
+    let (mut reparsed, mut updated) = (0, 0);
+    while let Some(transfer) = cursor.try_next().await? {
+        let (Some(raw_topics), Some(raw_data)) = (&transfer.raw_topics, &transfer.raw_data) else {
+            continue;
+        };
+        let (transaction_hash, log_index) = (&transfer.transaction_hash, transfer.log_index);
+
+        let Some(contract) = transfer.contract.to_lowercase().parse::<H160>().ok().and_then(|address| parser.contract(&address)) else {
+            warn!(transaction = %transaction_hash, log_index, contract = %transfer.contract, "Skipping transfer of an unwatched contract");
+            continue;
+        };
+
+        let topics: Result<Vec<H256>, _> = raw_topics.iter().map(|topic| topic.parse()).collect();
+        let (Ok(topics), Ok(data)) = (topics, hex::decode(raw_data.trim_start_matches("0x"))) else {
+            warn!(transaction = %transaction_hash, log_index, "Skipping transfer with a malformed raw log");
+            continue;
+        };
+
+        let Some(DecodedTransfer { from, to, value, .. }) = parser.decode_transfer(contract, &topics, &data, transaction_hash, log_index) else {
+            continue;
+        };
+
+        let transfer_kind = TransferKind::of(&from, &to);
+        let (from, to) = (address_case.apply(from), address_case.apply(to));
+        let value_decimal = contract.normalize_value(value);
+        let value = value.to_string();
+
+        // Kept a string when it doesn't fit a Decimal128, like the sink does. Compared as stored, a Decimal128 rounds
+        // amounts past 34 significant digits.
+        let (value_decimal, stored_value_decimal) = match decimal128.then(|| models::decimal128(&value_decimal)).flatten() {
+            Some(amount) => (amount.to_string(), Bson::Decimal128(amount)),
+            None => (value_decimal.clone(), Bson::String(value_decimal)),
+        };
+
+        reparsed += 1;
+
+        if from != transfer.from || to != transfer.to || value != transfer.value || value_decimal != transfer.value_decimal || transfer_kind != transfer.transfer_kind {
+            let mut update_filter = chain_filter(chain_id);
+            update_filter.insert("transaction_hash", transaction_hash);
+            update_filter.insert("log_index", log_index as i64);
+
+            collection
+                .update_one(
+                    update_filter,
+                    doc! { "$set": { "from": from, "to": to, "value": value, "value_decimal": stored_value_decimal, "transfer_kind": transfer_kind.as_str() } },
+                    None,
+                )
+                .await?;
+
+            updated += 1;
+        }
+    }
+
+    Ok((reparsed, updated))
+}
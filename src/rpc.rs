@@ -0,0 +1,252 @@
+use std::future::Future;
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use futures::StreamExt;
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+use web3::api::{Eth, SubscriptionStream};
+use web3::error::TransportError;
+use web3::transports::{Either, Http, WebSocket};
+use web3::types::BlockHeader;
+use web3::Web3;
+use tracing::{error, info, warn};
+use crate::metrics::METRICS;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// WebSocket for `ws://` and `wss://` endpoints, HTTP for `http://` and `https://` ones.
+pub type RpcTransport = Either<WebSocket, Http>;
+
+/// Whether `url` is an HTTP endpoint, which can't serve subscriptions.
+pub fn is_http(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+async fn connect_transport(url: &str) -> web3::Result<RpcTransport> {
+    match is_http(url) {
+        true => Ok(Either::Right(Http::new(url)?)),
+        false => Ok(Either::Left(WebSocket::new(url).await?)),
+    }
+}
+
+/// A WebSocket or HTTP provider that reconnects with exponential backoff instead of failing the indexer,
+/// failing over to the next endpoint in `urls` whenever the active one errors or disconnects.
+pub struct Rpc {
+    urls: Vec<String>,
+    active: AtomicUsize,
+    max_retries: Option<u32>,
+    limiter: Option<DefaultDirectRateLimiter>,
+    web3: Mutex<Web3<RpcTransport>>,
+}
+
+impl Rpc {
+    /// Connects to the first reachable endpoint. With `rps` set, requests are throttled to that many per second.
+    pub async fn connect(urls: Vec<String>, max_retries: Option<u32>, rps: Option<NonZeroU32>) -> Rpc {
+        assert!(!urls.is_empty(), "At least one RPC endpoint is required");
+
+        let mut attempt = 0;
+        let mut active = 0;
+
+        loop {
+            let url = &urls[active];
+
+            match connect_transport(url).await {
+                Ok(transport) => {
+                    info!(url = %url, "Connected to RPC node");
+
+                    return Rpc {
+                        active: AtomicUsize::new(active),
+                        urls,
+                        max_retries,
+                        limiter: rps.map(|rps| RateLimiter::direct(Quota::per_second(rps))),
+                        web3: Mutex::new(Web3::new(transport)),
+                    }
+                }
+                Err(e) => {
+                    METRICS.rpc_errors.inc();
+                    METRICS.rpc_request_errors.with_label_values(&[url]).inc();
+
+                    if !backoff(url, max_retries, &mut attempt, &e).await {
+                        give_up(url, max_retries.unwrap_or_default(), &e);
+                    }
+
+                    active = (active + 1) % urls.len();
+                }
+            }
+        }
+    }
+
+    /// The endpoint currently in use.
+    fn url(&self) -> &str {
+        &self.urls[self.active.load(Ordering::SeqCst)]
+    }
+
+    /// Runs an `eth` call, retrying and reconnecting on failure until it succeeds or the
+    /// configured number of retries is exhausted, in which case the process exits.
+    pub async fn call<T, F, Fut>(&self, f: F) -> T
+    where
+        F: Fn(Eth<RpcTransport>) -> Fut,
+        Fut: Future<Output = web3::Result<T>>,
+    {
+        match self.retry(self.max_retries, |web3| f(web3.eth())).await {
+            Ok(result) => result,
+            Err(e) => give_up(self.url(), self.max_retries.unwrap_or_default(), &e),
+        }
+    }
+
+    /// Like `call`, but returns the error to the caller after `retries` failed retries.
+    pub async fn try_call<T, F, Fut>(&self, retries: u32, f: F) -> web3::Result<T>
+    where
+        F: Fn(Eth<RpcTransport>) -> Fut,
+        Fut: Future<Output = web3::Result<T>>,
+    {
+        self.retry(Some(retries), |web3| f(web3.eth())).await
+    }
+
+    /// Subscribes to new chain heads, retrying like `call`. Only WebSocket endpoints support subscriptions.
+    pub async fn subscribe_new_heads(&self) -> SubscriptionStream<WebSocket, BlockHeader> {
+        let subscribe = |web3: Web3<RpcTransport>| async move {
+            match web3.transport() {
+                Either::Left(ws) => Web3::new(ws.clone()).eth_subscribe().subscribe_new_heads().await,
+                Either::Right(_) => Err(web3::Error::Transport(TransportError::Message("HTTP endpoints don't support subscriptions".to_string()))),
+            }
+        };
+
+        match self.retry(self.max_retries, subscribe).await {
+            Ok(heads) => heads,
+            Err(e) => give_up(self.url(), self.max_retries.unwrap_or_default(), &e),
+        }
+    }
+
+    /// Waits for the next head number on `heads`, resubscribing when the subscription fails or closes.
+    pub async fn next_head(&self, heads: &mut SubscriptionStream<WebSocket, BlockHeader>) -> u64 {
+        loop {
+            match heads.next().await {
+                Some(Ok(header)) => {
+                    if let Some(number) = header.number {
+                        return number.as_u64();
+                    }
+                }
+                Some(Err(e)) => {
+                    METRICS.rpc_errors.inc();
+                    warn!(url = %self.url(), error = %e, "New head subscription failed, resubscribing");
+                    *heads = self.subscribe_new_heads().await;
+                }
+                None => {
+                    warn!(url = %self.url(), "New head subscription closed, resubscribing");
+                    *heads = self.subscribe_new_heads().await;
+                }
+            }
+        }
+    }
+
+    async fn retry<T, F, Fut>(&self, max_retries: Option<u32>, f: F) -> web3::Result<T>
+    where
+        F: Fn(Web3<RpcTransport>) -> Fut,
+        Fut: Future<Output = web3::Result<T>>,
+    {
+        let mut attempt = 0;
+
+        loop {
+            if let Some(limiter) = &self.limiter {
+                limiter.until_ready().await;
+            }
+
+            let web3 = self.web3.lock().unwrap().clone();
+
+            match self.timed(f(web3)).await {
+                Ok(result) => return Ok(result),
+                Err(e) => {
+                    METRICS.rpc_errors.inc();
+
+                    if !backoff(self.url(), max_retries, &mut attempt, &e).await {
+                        return Err(e);
+                    }
+
+                    if matches!(e, web3::Error::Transport(_) | web3::Error::Io(_)) {
+                        self.reconnect().await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Awaits a request against the active endpoint, recording its latency and whether it failed for that endpoint.
+    async fn timed<T>(&self, request: impl Future<Output = web3::Result<T>>) -> web3::Result<T> {
+        let url = self.url();
+        let timer = METRICS.rpc_request_duration.with_label_values(&[url]).start_timer();
+
+        let result = request.await;
+
+        timer.observe_duration();
+        METRICS.rpc_requests.with_label_values(&[url]).inc();
+        if result.is_err() {
+            METRICS.rpc_request_errors.with_label_values(&[url]).inc();
+        }
+
+        result
+    }
+
+    /// Logs the request count, error count and average latency of every endpoint used so far, to tell a
+    /// flaky provider in the failover list apart from the healthy ones. Failed connections count as errors.
+    pub fn log_endpoint_stats(&self) {
+        for url in &self.urls {
+            let requests = METRICS.rpc_requests.with_label_values(&[url]).get();
+            let errors = METRICS.rpc_request_errors.with_label_values(&[url]).get();
+            if requests == 0 && errors == 0 {
+                continue;
+            }
+
+            let duration = METRICS.rpc_request_duration.with_label_values(&[url]);
+            let avg_latency_ms = (requests > 0).then(|| (duration.get_sample_sum() / requests as f64 * 10_000.0).round() / 10.0);
+
+            info!(url = %url, requests, errors, avg_latency_ms, "RPC endpoint stats");
+        }
+    }
+
+    /// Reconnects to the next endpoint, or the same one when only one is configured. If that fails too
+    /// the stale connection is kept, so the next attempt errors again and moves on to the following endpoint.
+    async fn reconnect(&self) {
+        let previous = self.active.load(Ordering::SeqCst);
+        let next = (previous + 1) % self.urls.len();
+        let url = &self.urls[next];
+
+        if next != previous {
+            warn!(from = %self.urls[previous], to = %url, "Failing over to the next RPC node");
+        }
+
+        self.active.store(next, Ordering::SeqCst);
+
+        match connect_transport(url).await {
+            Ok(transport) => {
+                info!(url = %url, "Reconnected to RPC node");
+                *self.web3.lock().unwrap() = Web3::new(transport);
+            }
+            Err(e) => {
+                METRICS.rpc_request_errors.with_label_values(&[url]).inc();
+                error!(url = %url, error = %e, "Failed to reconnect to RPC node");
+            }
+        }
+    }
+}
+
+/// Sleeps before the next attempt, or returns false once `max_retries` is exhausted.
+async fn backoff(url: &str, max_retries: Option<u32>, attempt: &mut u32, error: &web3::Error) -> bool {
+    *attempt += 1;
+
+    if max_retries.is_some_and(|max_retries| *attempt > max_retries) {
+        return false;
+    }
+
+    let delay = Duration::from_secs(1 << (*attempt - 1).min(6)).min(MAX_BACKOFF);
+    warn!(url, %error, attempt = *attempt, delay_secs = delay.as_secs(), "RPC error, retrying");
+    tokio::time::sleep(delay).await;
+
+    true
+}
+
+fn give_up(url: &str, retries: u32, error: &web3::Error) -> ! {
+    error!(url, retries, %error, "Giving up on RPC node");
+    std::process::exit(1);
+}
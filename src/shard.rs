@@ -0,0 +1,50 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// One of `count` disjoint slices of a block range, written as `index/count` with a zero-based index.
+#[derive(Clone, Copy, Debug)]
+pub struct Shard {
+    pub index: u64,
+    pub count: u64,
+}
+
+impl Shard {
+    /// The inclusive block range of this shard within `start_block..=end_block`, `None` if the range is
+    /// too short to leave any blocks for it.
+    pub fn range(&self, start_block: u64, end_block: u64) -> Option<(u64, u64)> {
+        let size = (end_block - start_block + 1).div_ceil(self.count);
+        let shard_start = start_block + self.index * size;
+
+        (shard_start <= end_block).then(|| (shard_start, (shard_start + size - 1).min(end_block)))
+    }
+
+    /// Appended to checkpoint ids so shards don't overwrite each other's progress.
+    pub fn checkpoint_suffix(&self) -> String {
+        format!("-shard-{}-of-{}", self.index, self.count)
+    }
+}
+
+impl FromStr for Shard {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Shard, String> {
+        let (index, count) = value
+            .split_once('/')
+            .ok_or_else(|| format!("expected index/count, got {:?}", value))?;
+
+        let index: u64 = index.parse().map_err(|_| format!("invalid shard index {:?}", index))?;
+        let count: u64 = count.parse().map_err(|_| format!("invalid shard count {:?}", count))?;
+
+        if index >= count {
+            return Err(format!("shard index {} must be smaller than the shard count {}", index, count));
+        }
+
+        Ok(Shard { index, count })
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
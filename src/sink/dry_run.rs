@@ -0,0 +1,70 @@
+use async_trait::async_trait;
+use tracing::{debug, info};
+use crate::models::{AddressActivity, Approval, BlockStats, DailyVolume, GenericEvent, Transfer};
+use crate::sink::{SinkResult, TransferSink};
+
+/// Logs what would have been written instead of touching a database.
+#[derive(Default)]
+pub struct DryRunSink {
+    total_transfers: u64,
+    total_approvals: u64,
+    total_events: u64,
+}
+
+#[async_trait]
+impl TransferSink for DryRunSink {
+    async fn insert_batch(&mut self, transfers: &[Transfer]) -> SinkResult<()> {
+        for transfer in transfers {
+            debug!(
+                contract = %transfer.contract,
+                from = %transfer.from,
+                to = %transfer.to,
+                value = %transfer.value_decimal,
+                block = transfer.block_number,
+                transaction = %transfer.transaction_hash,
+                "Would insert transfer"
+            );
+        }
+
+        self.total_transfers += transfers.len() as u64;
+        info!(transfers = transfers.len(), total_transfers = self.total_transfers, "Dry run, skipped inserting transfers");
+
+        Ok(())
+    }
+
+    async fn insert_approvals(&mut self, approvals: &[Approval]) -> SinkResult<()> {
+        self.total_approvals += approvals.len() as u64;
+        info!(approvals = approvals.len(), total_approvals = self.total_approvals, "Dry run, skipped inserting approvals");
+
+        Ok(())
+    }
+
+    async fn insert_events(&mut self, events: &[GenericEvent]) -> SinkResult<()> {
+        self.total_events += events.len() as u64;
+        info!(events = events.len(), total_events = self.total_events, "Dry run, skipped inserting events");
+
+        Ok(())
+    }
+
+    async fn insert_block_stats(&mut self, stats: &[BlockStats]) -> SinkResult<()> {
+        debug!(blocks = stats.len(), "Dry run, skipped inserting block stats");
+
+        Ok(())
+    }
+
+    async fn insert_address_activity(&mut self, activity: &[AddressActivity]) -> SinkResult<()> {
+        debug!(addresses = activity.len(), "Dry run, skipped updating address activity");
+
+        Ok(())
+    }
+
+    async fn insert_daily_volume(&mut self, volume: &[DailyVolume]) -> SinkResult<()> {
+        debug!(days = volume.len(), "Dry run, skipped updating daily volume");
+
+        Ok(())
+    }
+
+    async fn delete_orphaned(&mut self, _chain_id: Option<u64>, _block_number: u64, _block_hash: &str) -> SinkResult<Vec<(String, u64)>> {
+        Ok(vec![])
+    }
+}
@@ -0,0 +1,61 @@
+use std::time::Duration;
+use async_trait::async_trait;
+use futures::future::try_join_all;
+use rdkafka::producer::{DeliveryFuture, FutureProducer, FutureRecord, Producer};
+use rdkafka::ClientConfig;
+use crate::models::Transfer;
+use crate::sink::{SinkResult, TransferSink};
+
+const KAFKA_FLUSH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Publishes transfers as JSON to a Kafka topic, keyed by transaction hash so a transaction's transfers share a partition.
+pub struct KafkaSink {
+    producer: FutureProducer,
+    topic: String,
+    pending: Vec<DeliveryFuture>,
+}
+
+impl KafkaSink {
+    pub fn connect(brokers: &str, topic: &str) -> SinkResult<KafkaSink> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("message.timeout.ms", "30000")
+            .create()?;
+
+        Ok(KafkaSink {
+            producer,
+            topic: topic.to_string(),
+            pending: vec![],
+        })
+    }
+}
+
+#[async_trait]
+impl TransferSink for KafkaSink {
+    async fn insert_batch(&mut self, transfers: &[Transfer]) -> SinkResult<()> {
+        for transfer in transfers {
+            let payload = serde_json::to_string(transfer)?;
+            let record = FutureRecord::to(&self.topic)
+                .key(&transfer.transaction_hash)
+                .payload(&payload);
+
+            let delivery = self.producer.send_result(record).map_err(|(e, _)| e)?;
+            self.pending.push(delivery);
+        }
+
+        Ok(())
+    }
+
+    /// Waits until every message of the batch is acknowledged by the brokers.
+    async fn flush(&mut self) -> SinkResult<()> {
+        let deliveries = try_join_all(self.pending.drain(..)).await?;
+        for delivery in deliveries {
+            delivery.map_err(|(e, _)| e)?;
+        }
+
+        let producer = self.producer.clone();
+        tokio::task::spawn_blocking(move || producer.flush(KAFKA_FLUSH_TIMEOUT)).await??;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+mod dry_run;
+mod kafka;
+mod mongo;
+mod parquet;
+mod postgres;
+mod sqlite;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use async_trait::async_trait;
+use clap::ValueEnum;
+use crate::models::{AddressActivity, Approval, BlockStats, DailyVolume, GenericEvent, Reorg, Transfer};
+
+pub use dry_run::DryRunSink;
+pub use kafka::KafkaSink;
+pub use mongo::{suffixed_collection_name, MongoCollections, MongoSink};
+pub(crate) use mongo::chain_filter;
+pub use parquet::{ParquetPartition, ParquetSink};
+pub use postgres::PostgresSink;
+pub use sqlite::SqliteSink;
+
+pub type SinkResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Backend {
+    Mongo,
+    Postgres,
+    Kafka,
+    Sqlite,
+    Parquet,
+}
+
+/// Counts how often each orphaned block hash was returned by a delete, for `delete_orphaned`.
+fn count_hashes(hashes: Vec<String>) -> Vec<(String, u64)> {
+    let mut counts = BTreeMap::new();
+    for hash in hashes {
+        *counts.entry(hash).or_default() += 1;
+    }
+
+    counts.into_iter().collect()
+}
+
+/// A destination for indexed records. The indexer writes batches through `insert_batch` and calls
+/// `flush` before saving a checkpoint, so a checkpoint never covers records that aren't persisted.
+#[async_trait]
+pub trait TransferSink: Send + Sync {
+    async fn insert_batch(&mut self, transfers: &[Transfer]) -> SinkResult<()>;
+
+    async fn flush(&mut self) -> SinkResult<()> {
+        Ok(())
+    }
+
+    async fn insert_approvals(&mut self, _approvals: &[Approval]) -> SinkResult<()> {
+        Err("approvals are not supported by this backend".into())
+    }
+
+    async fn insert_events(&mut self, _events: &[GenericEvent]) -> SinkResult<()> {
+        Err("ABI events are not supported by this backend".into())
+    }
+
+    /// Sinks without block stats support silently skip them.
+    async fn insert_block_stats(&mut self, _stats: &[BlockStats]) -> SinkResult<()> {
+        Ok(())
+    }
+
+    /// Merges per address activity of a batch into the stored activity, keeping the earliest first and latest last block
+    /// and adding up transfer counts.
+    async fn insert_address_activity(&mut self, _activity: &[AddressActivity]) -> SinkResult<()> {
+        Err("address activity is not supported by this backend".into())
+    }
+
+    /// Adds the volume and transfer count of every contract and day of a batch to the stored ones.
+    async fn insert_daily_volume(&mut self, _volume: &[DailyVolume]) -> SinkResult<()> {
+        Err("daily volume is not supported by this backend".into())
+    }
+
+    /// Removes transfers of `chain_id` stored for `block_number` under a different block hash, returning every orphaned
+    /// hash with the number of transfers removed.
+    async fn delete_orphaned(&mut self, _chain_id: Option<u64>, _block_number: u64, _block_hash: &str) -> SinkResult<Vec<(String, u64)>> {
+        Err("reorg checks are not supported by this backend".into())
+    }
+
+    /// Sinks without a reorg audit trail silently skip them.
+    async fn insert_reorgs(&mut self, _reorgs: &[Reorg]) -> SinkResult<()> {
+        Ok(())
+    }
+
+    /// Removes all transfers of `chain_id` stored for `block_number`, returning how many were removed.
+    async fn delete_block(&mut self, _chain_id: Option<u64>, _block_number: u64) -> SinkResult<u64> {
+        Err("deleting blocks is not supported by this backend".into())
+    }
+
+    /// Removes up to `limit` transfers of `chain_id` below `before_block`, returning how many were removed. Aggregates
+    /// like block stats, address activity and daily volume are left as they are.
+    async fn prune_transfers(&mut self, _chain_id: Option<u64>, _before_block: u64, _limit: usize) -> SinkResult<u64> {
+        Err("pruning is not supported by this backend".into())
+    }
+
+    /// Whether `error` means a write was too large for the backend, e.g. it exceeded a size limit or timed out, so writing
+    /// the same records in smaller chunks may succeed.
+    fn is_too_large(&self, _error: &(dyn Error + Send + Sync + 'static)) -> bool {
+        false
+    }
+
+    /// Sinks without checkpoint support always start from the configured start block.
+    async fn load_checkpoint(&self) -> SinkResult<Option<u64>> {
+        Ok(None)
+    }
+
+    async fn save_checkpoint(&mut self, _block_number: u64) -> SinkResult<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,565 @@
+use std::error::Error;
+use async_trait::async_trait;
+use std::str::FromStr;
+use bigdecimal::num_bigint::BigInt;
+use bigdecimal::BigDecimal;
+use futures::{stream, StreamExt, TryStreamExt};
+use mongodb::{Client, Collection};
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::error::ErrorKind;
+use mongodb::options::{ClientOptions, FindOptions, IndexOptions, InsertManyOptions, ReplaceOptions, UpdateOptions};
+use serde::{Serialize, Deserialize};
+use tracing::{info, warn};
+use crate::models::{decimal128, AddressActivity, Approval, BlockStats, DailyVolume, GenericEvent, Reorg, Transfer};
+use crate::sink::{SinkResult, TransferSink};
+
+const MONGO_DB_APPROVAL_COLLECTION_NAME: &str = "approvals";
+const MONGO_DB_CHECKPOINT_COLLECTION_NAME: &str = "checkpoints";
+const MONGO_DB_BLOCK_STATS_COLLECTION_NAME: &str = "block_stats";
+const MONGO_DB_EVENT_COLLECTION_NAME: &str = "events";
+const MONGO_DB_ADDRESS_ACTIVITY_COLLECTION_NAME: &str = "address_activity";
+const MONGO_DB_DAILY_VOLUME_COLLECTION_NAME: &str = "daily_volume";
+const MONGO_DB_REORG_COLLECTION_NAME: &str = "reorgs";
+
+// MongoDB has no bulk upsert in this driver version, so aggregates are upserted with concurrent updates.
+const MONGO_UPSERT_CONCURRENCY: usize = 32;
+
+const DUPLICATE_KEY_ERROR_CODE: i32 = 11000;
+const NAMESPACE_NOT_FOUND_ERROR_CODE: i32 = 26;
+const INDEX_NOT_FOUND_ERROR_CODE: i32 = 27;
+
+// The unique index on a log's position created before chain_id was part of it, which rejects the same position on
+// another chain.
+const POSITION_INDEX_WITHOUT_CHAIN: &str = "transaction_hash_1_log_index_1";
+
+// MaxTimeMSExpired, NetworkTimeout, ExceededTimeLimit and BSONObjectTooLarge.
+const TOO_LARGE_ERROR_CODES: [i32; 4] = [50, 89, 262, 10334];
+
+/// The name of collection `name` with `suffix` appended, e.g. `transfers_ronin`.
+pub fn suffixed_collection_name(name: &str, suffix: Option<&str>) -> String {
+    match suffix {
+        Some(suffix) => format!("{}_{}", name, suffix),
+        None => name.to_string(),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    #[serde(rename = "_id")]
+    id: String,
+    block_number: u64
+}
+
+pub struct IndexModel {
+    pub model: Document,
+    pub options: IndexOptions,
+}
+
+fn index_model(key: &'static str, unique: bool) -> IndexModel {
+    compound_index_model(&[key], unique)
+}
+
+fn compound_index_model(keys: &[&'static str], unique: bool) -> IndexModel {
+    let mut doc = Document::new();
+    for key in keys {
+        doc.insert(*key, 1u32);
+    }
+
+    IndexModel {
+        model: doc,
+        options: match unique {
+            true => IndexOptions::builder().unique(true).build(),
+            false => Default::default(),
+        },
+    }
+}
+
+/// Creates the indexes of `indexes` that `collection` doesn't have yet, matched by their default name, e.g.
+/// `contract_1`. An existing index of the same name but a different uniqueness is an error, it has to be dropped first.
+async fn create_indexes<T>(collection: &Collection<T>, indexes: Vec<IndexModel>) -> SinkResult<()> {
+    let existing: Vec<mongodb::IndexModel> = match collection.list_indexes(None).await {
+        Ok(cursor) => cursor.try_collect().await?,
+        // The collection is created along with its first index.
+        Err(e) if matches!(e.kind.as_ref(), ErrorKind::Command(error) if error.code == NAMESPACE_NOT_FOUND_ERROR_CODE) => vec![],
+        Err(e) => return Err(e.into()),
+    };
+
+    let (mut created, mut present) = (vec![], vec![]);
+    for model in indexes {
+        let name = model.model.iter().map(|(key, value)| format!("{}_{}", key, value)).collect::<Vec<_>>().join("_");
+        let unique = model.options.unique.unwrap_or_default();
+
+        match existing.iter().filter_map(|index| index.options.as_ref()).find(|options| options.name.as_deref() == Some(name.as_str())) {
+            Some(options) if options.unique.unwrap_or_default() != unique => {
+                return Err(format!("Index {} of {} exists with unique = {}, expected {}", name, collection.name(), !unique, unique).into());
+            }
+            Some(_) => present.push(name),
+            None => {
+                let result = collection.create_index(mongodb::IndexModel::builder().keys(model.model).options(model.options).build(), None).await?;
+                info!(collection = collection.name(), index = %result.index_name, "Created index");
+                created.push(result.index_name);
+            }
+        }
+    }
+
+    info!(collection = collection.name(), created = created.len(), present = %present.join(","), "Indexes ready");
+
+    Ok(())
+}
+
+/// Drops index `name` of `collection` if it exists, e.g. one replaced by an index on more keys.
+async fn drop_index<T>(collection: &Collection<T>, name: &str) -> SinkResult<()> {
+    match collection.drop_index(name, None).await {
+        Ok(_) => info!(collection = collection.name(), index = name, "Dropped index"),
+        Err(e) if matches!(e.kind.as_ref(), ErrorKind::Command(error) if [INDEX_NOT_FOUND_ERROR_CODE, NAMESPACE_NOT_FOUND_ERROR_CODE].contains(&error.code)) => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    Ok(())
+}
+
+/// Matches records of `chain_id`, or records without a chain id when none is configured.
+pub(crate) fn chain_filter(chain_id: Option<u64>) -> Document {
+    match chain_id {
+        Some(chain_id) => doc! { "chain_id": chain_id as i64 },
+        None => doc! { "chain_id": { "$exists": false } },
+    }
+}
+
+/// Treats a failed insert as successful when every failure is a duplicate key, e.g. when re-processing blocks after a
+/// restart, returning the indexes of the records that were already stored.
+fn ignore_duplicates<T>(result: mongodb::error::Result<T>) -> SinkResult<Vec<usize>> {
+    match result {
+        Ok(_) => Ok(vec![]),
+        Err(e) => match e.kind.as_ref() {
+            ErrorKind::BulkWrite(failure) if failure.write_concern_error.is_none() => match &failure.write_errors {
+                Some(errors) if errors.iter().all(|error| error.code == DUPLICATE_KEY_ERROR_CODE) => Ok(errors.iter().map(|error| error.index).collect()),
+                _ => Err(e.into()),
+            },
+            _ => Err(e.into()),
+        },
+    }
+}
+
+/// Inserts `records` skipping duplicates, returning how many were already stored. An ordered insert stops at the first
+/// duplicate, so the records after it are inserted again until all were tried.
+async fn insert_many<T: Serialize + Send + Sync>(collection: &Collection<T>, records: &[T], ordered: bool) -> SinkResult<usize> {
+    let options = InsertManyOptions::builder().ordered(ordered).build();
+
+    let (mut remaining, mut duplicates) = (records, 0);
+    while !remaining.is_empty() {
+        let stored = ignore_duplicates(collection.insert_many(remaining, options.clone()).await)?;
+        duplicates += stored.len();
+
+        match (ordered, stored.last()) {
+            (true, Some(index)) => remaining = &remaining[index + 1..],
+            _ => break,
+        }
+    }
+
+    Ok(duplicates)
+}
+
+fn log_duplicates(duplicates: usize, records: &str) {
+    if duplicates > 0 {
+        info!(duplicates, records, "Skipped records that were already stored");
+    }
+}
+
+/// The optional collections to create indexes for.
+#[derive(Default, Clone, Copy)]
+pub struct MongoCollections {
+    pub approvals: bool,
+    pub events: bool,
+    pub address_activity: bool,
+    pub daily_volume: bool,
+}
+
+pub struct MongoSink {
+    transfers: Collection<Transfer>,
+    approvals: Collection<Approval>,
+    checkpoints: Collection<Checkpoint>,
+    block_stats: Collection<BlockStats>,
+    events: Collection<GenericEvent>,
+    address_activity: Collection<AddressActivity>,
+    daily_volume: Collection<DailyVolume>,
+    reorgs: Collection<Reorg>,
+    checkpoint_id: String,
+    decimal128: bool,
+    ordered: bool,
+}
+
+impl MongoSink {
+    /// Every collection name gets `collection_suffix` appended. Checkpoints stay in one shared collection, keyed by the
+    /// suffixed transfers collection.
+    pub async fn connect(
+        options: ClientOptions,
+        db_name: &str,
+        collection_name: &str,
+        collection_suffix: Option<&str>,
+        collections: MongoCollections,
+        checkpoint_suffix: &str,
+    ) -> SinkResult<MongoSink> {
+        let db_client = Client::with_options(options)?;
+        let db_db = db_client.database(db_name);
+        let collection_name = suffixed_collection_name(collection_name, collection_suffix);
+        let name = |name: &str| suffixed_collection_name(name, collection_suffix);
+
+        let sink = MongoSink {
+            transfers: db_db.collection::<Transfer>(&collection_name),
+            approvals: db_db.collection::<Approval>(&name(MONGO_DB_APPROVAL_COLLECTION_NAME)),
+            checkpoints: db_db.collection::<Checkpoint>(MONGO_DB_CHECKPOINT_COLLECTION_NAME),
+            block_stats: db_db.collection::<BlockStats>(&name(MONGO_DB_BLOCK_STATS_COLLECTION_NAME)),
+            events: db_db.collection::<GenericEvent>(&name(MONGO_DB_EVENT_COLLECTION_NAME)),
+            address_activity: db_db.collection::<AddressActivity>(&name(MONGO_DB_ADDRESS_ACTIVITY_COLLECTION_NAME)),
+            daily_volume: db_db.collection::<DailyVolume>(&name(MONGO_DB_DAILY_VOLUME_COLLECTION_NAME)),
+            reorgs: db_db.collection::<Reorg>(&name(MONGO_DB_REORG_COLLECTION_NAME)),
+            checkpoint_id: format!("{}{}", collection_name, checkpoint_suffix),
+            decimal128: false,
+            ordered: false,
+        };
+
+        drop_index(&sink.transfers, POSITION_INDEX_WITHOUT_CHAIN).await?;
+        create_indexes(&sink.transfers, vec![
+            index_model("contract", false),
+            index_model("from", false),
+            index_model("to", false),
+            index_model("value", false),
+            index_model("timestamp", false),
+            index_model("block_number", false),
+            index_model("transaction_hash", false),
+            index_model("tx_from", false),
+            index_model("transfer_kind", false),
+            index_model("chain_id", false),
+            compound_index_model(&["chain_id", "transaction_hash", "log_index"], true)
+        ]).await?;
+
+        create_indexes(&sink.block_stats, vec![
+            compound_index_model(&["chain_id", "block_number"], true),
+            index_model("timestamp", false)
+        ]).await?;
+
+        if collections.approvals {
+            drop_index(&sink.approvals, POSITION_INDEX_WITHOUT_CHAIN).await?;
+            create_indexes(&sink.approvals, vec![
+                index_model("contract", false),
+                index_model("owner", false),
+                index_model("spender", false),
+                index_model("timestamp", false),
+                index_model("block_number", false),
+                index_model("transaction_hash", false),
+                compound_index_model(&["chain_id", "transaction_hash", "log_index"], true)
+            ]).await?;
+        }
+
+        if collections.events {
+            drop_index(&sink.events, POSITION_INDEX_WITHOUT_CHAIN).await?;
+            create_indexes(&sink.events, vec![
+                index_model("contract", false),
+                index_model("event", false),
+                index_model("timestamp", false),
+                index_model("block_number", false),
+                index_model("transaction_hash", false),
+                compound_index_model(&["chain_id", "transaction_hash", "log_index"], true)
+            ]).await?;
+        }
+
+        if collections.address_activity {
+            create_indexes(&sink.address_activity, vec![
+                compound_index_model(&["chain_id", "address"], true),
+                index_model("first_block", false),
+                index_model("last_block", false),
+            ]).await?;
+        }
+
+        if collections.daily_volume {
+            create_indexes(&sink.daily_volume, vec![
+                compound_index_model(&["chain_id", "contract", "day"], true),
+                index_model("day", false),
+            ]).await?;
+        }
+
+        Ok(sink)
+    }
+
+    /// Stores `value_decimal` of transfers as a Decimal128 so amounts can be compared and sorted in queries. The raw
+    /// `value` stays a string, it can exceed the 34 significant digits of a Decimal128.
+    pub fn with_decimal128(mut self, decimal128: bool) -> MongoSink {
+        self.decimal128 = decimal128;
+        self
+    }
+
+    /// Inserts records in order, so a failing record stops the insert of the records after it. Duplicates are still
+    /// skipped by inserting the rest again. Unordered inserts are faster and the default.
+    pub fn with_ordered_inserts(mut self, ordered: bool) -> MongoSink {
+        self.ordered = ordered;
+        self
+    }
+
+    /// Returns the inclusive ranges of blocks in `from_block..=to_block` that have no block stats, i.e. were never indexed.
+    pub async fn missing_blocks(&self, chain_id: Option<u64>, from_block: u64, to_block: u64) -> SinkResult<Vec<(u64, u64)>> {
+        let options = FindOptions::builder()
+            .sort(doc! { "block_number": 1 })
+            .build();
+
+        let mut filter = chain_filter(chain_id);
+        filter.insert("block_number", doc! { "$gte": from_block as i64, "$lte": to_block as i64 });
+
+        let mut cursor = self.block_stats.find(filter, options).await?;
+
+        let mut gaps = vec![];
+        let mut expected = from_block;
+        while let Some(stats) = cursor.try_next().await? {
+            if stats.block_number > expected {
+                gaps.push((expected, stats.block_number - 1));
+            }
+
+            expected = expected.max(stats.block_number + 1);
+        }
+
+        if expected <= to_block {
+            gaps.push((expected, to_block));
+        }
+
+        Ok(gaps)
+    }
+
+    /// Sums the transfers of `contract` to and from `address` up to `at_block` in an aggregation pipeline, returning the
+    /// raw balance they add up to and the number of transfers. Values are summed as Decimal128, which keeps 34
+    /// significant digits, so larger raw amounts are rounded.
+    pub async fn balance(&self, chain_id: Option<u64>, contract: &str, address: &str, at_block: Option<u64>) -> SinkResult<(BigInt, u64)> {
+        let mut filter = chain_filter(chain_id);
+        filter.insert("contract", contract);
+        filter.insert("$or", vec![doc! { "from": address }, doc! { "to": address }]);
+        if let Some(at_block) = at_block {
+            filter.insert("block_number", doc! { "$lte": at_block as i64 });
+        }
+
+        // Received values count positive and sent ones negative, a transfer to itself adds up to zero.
+        let direction = doc! { "$subtract": [
+            { "$cond": [{ "$eq": ["$to", address] }, 1, 0] },
+            { "$cond": [{ "$eq": ["$from", address] }, 1, 0] },
+        ] };
+
+        let pipeline = [
+            doc! { "$match": filter },
+            doc! { "$group": {
+                "_id": Bson::Null,
+                "balance": { "$sum": { "$multiply": [{ "$toDecimal": "$value" }, direction] } },
+                "transfers": { "$sum": 1 },
+            } },
+        ];
+
+        let Some(result) = self.transfers.aggregate(pipeline, None).await?.try_next().await? else {
+            return Ok((BigInt::default(), 0));
+        };
+
+        let balance = match result.get("balance") {
+            Some(Bson::Decimal128(balance)) => BigDecimal::from_str(&balance.to_string())?,
+            balance => return Err(format!("Unexpected balance {:?}", balance).into()),
+        };
+        let (balance, _) = balance.with_scale(0).into_bigint_and_exponent();
+
+        Ok((balance, result.get_i32("transfers")? as u64))
+    }
+}
+
+#[async_trait]
+impl TransferSink for MongoSink {
+    /// Inserts unordered unless configured otherwise, so a transfer stored before a crash doesn't abort the rest of the
+    /// batch.
+    async fn insert_batch(&mut self, transfers: &[Transfer]) -> SinkResult<()> {
+        if !self.decimal128 {
+            log_duplicates(insert_many(&self.transfers, transfers, self.ordered).await?, "transfers");
+            return Ok(());
+        }
+
+        let mut unrepresentable = 0;
+        let mut documents = Vec::with_capacity(transfers.len());
+        for transfer in transfers {
+            let mut document = mongodb::bson::to_document(transfer)?;
+
+            // Keep the string when the amount doesn't fit, a range query skips the transfer but nothing is lost.
+            match decimal128(&transfer.value_decimal) {
+                Some(amount) => {
+                    document.insert("value_decimal", amount);
+                }
+                None => unrepresentable += 1,
+            }
+
+            documents.push(document);
+        }
+
+        if unrepresentable > 0 {
+            warn!(transfers = unrepresentable, "Stored amounts that don't fit a Decimal128 as strings");
+        }
+
+        let collection = self.transfers.clone_with_type::<Document>();
+        log_duplicates(insert_many(&collection, &documents, self.ordered).await?, "transfers");
+
+        Ok(())
+    }
+
+    fn is_too_large(&self, error: &(dyn Error + Send + Sync + 'static)) -> bool {
+        let Some(error) = error.downcast_ref::<mongodb::error::Error>() else {
+            return false;
+        };
+
+        match error.kind.as_ref() {
+            ErrorKind::Io(e) => e.kind() == std::io::ErrorKind::TimedOut,
+            ErrorKind::Command(e) => TOO_LARGE_ERROR_CODES.contains(&e.code),
+            ErrorKind::BulkWrite(failure) => failure.write_errors.iter().flatten().any(|e| TOO_LARGE_ERROR_CODES.contains(&e.code))
+                || failure.write_concern_error.as_ref().is_some_and(|e| TOO_LARGE_ERROR_CODES.contains(&e.code)),
+            _ => false,
+        }
+    }
+
+    async fn insert_approvals(&mut self, approvals: &[Approval]) -> SinkResult<()> {
+        log_duplicates(insert_many(&self.approvals, approvals, self.ordered).await?, "approvals");
+
+        Ok(())
+    }
+
+    async fn insert_events(&mut self, events: &[GenericEvent]) -> SinkResult<()> {
+        log_duplicates(insert_many(&self.events, events, self.ordered).await?, "events");
+
+        Ok(())
+    }
+
+    async fn insert_block_stats(&mut self, stats: &[BlockStats]) -> SinkResult<()> {
+        insert_many(&self.block_stats, stats, self.ordered).await?;
+
+        Ok(())
+    }
+
+    async fn insert_address_activity(&mut self, activity: &[AddressActivity]) -> SinkResult<()> {
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        let updates: Vec<_> = activity
+            .iter()
+            .map(|activity| {
+                let mut filter = chain_filter(activity.chain_id);
+                filter.insert("address", &activity.address);
+
+                let update = doc! {
+                    "$min": { "first_block": activity.first_block as i64 },
+                    "$max": { "last_block": activity.last_block as i64 },
+                    "$inc": { "transfer_count": activity.transfer_count as i64 },
+                };
+
+                self.address_activity.update_one(filter, update, options.clone())
+            })
+            .collect();
+
+        stream::iter(updates)
+            .buffer_unordered(MONGO_UPSERT_CONCURRENCY)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        Ok(())
+    }
+
+    /// Volumes are added as Decimal128 so they can be summed and compared in queries.
+    async fn insert_daily_volume(&mut self, volume: &[DailyVolume]) -> SinkResult<()> {
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        let updates = volume
+            .iter()
+            .map(|volume| {
+                let amount = decimal128(&volume.volume).ok_or_else(|| format!("Volume {} doesn't fit a Decimal128", volume.volume))?;
+
+                let mut filter = chain_filter(volume.chain_id);
+                filter.insert("contract", &volume.contract);
+                filter.insert("day", volume.day as i64);
+
+                let update = doc! {
+                    "$inc": { "volume": amount, "transfer_count": volume.transfer_count as i64 },
+                };
+
+                SinkResult::Ok(self.daily_volume.update_one(filter, update, options.clone()))
+            })
+            .collect::<SinkResult<Vec<_>>>()?;
+
+        stream::iter(updates)
+            .buffer_unordered(MONGO_UPSERT_CONCURRENCY)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_orphaned(&mut self, chain_id: Option<u64>, block_number: u64, block_hash: &str) -> SinkResult<Vec<(String, u64)>> {
+        let mut filter = chain_filter(chain_id);
+        filter.insert("block_number", block_number as i64);
+        filter.insert("block_hash", doc! { "$ne": block_hash });
+
+        let mut orphaned = vec![];
+        for orphaned_hash in self.transfers.distinct("block_hash", filter.clone(), None).await? {
+            let Some(orphaned_hash) = orphaned_hash.as_str() else {
+                continue;
+            };
+
+            let mut filter = filter.clone();
+            filter.insert("block_hash", orphaned_hash);
+
+            let result = self.transfers.delete_many(filter, None).await?;
+            orphaned.push((orphaned_hash.to_string(), result.deleted_count));
+        }
+
+        Ok(orphaned)
+    }
+
+    async fn insert_reorgs(&mut self, reorgs: &[Reorg]) -> SinkResult<()> {
+        self.reorgs.insert_many(reorgs, None).await?;
+
+        Ok(())
+    }
+
+    async fn delete_block(&mut self, chain_id: Option<u64>, block_number: u64) -> SinkResult<u64> {
+        let mut filter = chain_filter(chain_id);
+        filter.insert("block_number", block_number as i64);
+
+        let result = self.transfers.delete_many(filter, None).await?;
+
+        Ok(result.deleted_count)
+    }
+
+    async fn prune_transfers(&mut self, chain_id: Option<u64>, before_block: u64, limit: usize) -> SinkResult<u64> {
+        let mut filter = chain_filter(chain_id);
+        filter.insert("block_number", doc! { "$lt": before_block as i64 });
+
+        // delete_many has no limit, so the ids of a batch are looked up first to keep each delete short.
+        let options = FindOptions::builder().projection(doc! { "_id": 1 }).limit(limit as i64).build();
+        let ids: Vec<Document> = self.transfers.clone_with_type::<Document>().find(filter, options).await?.try_collect().await?;
+        let ids: Vec<_> = ids.into_iter().filter_map(|mut id| id.remove("_id")).collect();
+
+        if ids.is_empty() {
+            return Ok(0);
+        }
+
+        let result = self.transfers.delete_many(doc! { "_id": { "$in": ids } }, None).await?;
+
+        Ok(result.deleted_count)
+    }
+
+    async fn load_checkpoint(&self) -> SinkResult<Option<u64>> {
+        let checkpoint = self.checkpoints
+            .find_one(doc! { "_id": &self.checkpoint_id }, None)
+            .await?;
+
+        Ok(checkpoint.map(|checkpoint| checkpoint.block_number))
+    }
+
+    async fn save_checkpoint(&mut self, block_number: u64) -> SinkResult<()> {
+        let checkpoint = Checkpoint {
+            id: self.checkpoint_id.clone(),
+            block_number,
+        };
+
+        self.checkpoints
+            .replace_one(doc! { "_id": &self.checkpoint_id }, checkpoint, ReplaceOptions::builder().upsert(true).build())
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use arrow_array::{ArrayRef, RecordBatch, StringArray, UInt64Array, UInt8Array};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use async_trait::async_trait;
+use clap::ValueEnum;
+use mongodb::bson::DateTime;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use tokio::task::block_in_place;
+use crate::models::{TimestampUnit, Transfer};
+use crate::sink::{SinkResult, TransferSink};
+
+const PARQUET_CHECKPOINT_FILE: &str = "_checkpoint";
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ParquetPartition {
+    /// A directory per UTC day of the block timestamp, e.g. `day=2024-01-31`
+    Day,
+    /// A directory per `--parquet-partition-blocks` blocks, e.g. `blocks=1000000-1099999`
+    Blocks,
+}
+
+/// Writes transfers to Parquet files in a Hive style directory per partition, for analytics tools reading a data lake.
+/// Transfers are buffered until `flush`, which writes a new file named after its first and last block to every
+/// partition that received transfers. The checkpoint is a file next to the partitions.
+pub struct ParquetSink {
+    dir: PathBuf,
+    partition: ParquetPartition,
+    partition_blocks: u64,
+    timestamp_unit: TimestampUnit,
+    schema: SchemaRef,
+    /// Buffered transfers keyed by partition directory.
+    pending: BTreeMap<String, Vec<Transfer>>,
+    checkpoint_path: PathBuf,
+}
+
+impl ParquetSink {
+    pub fn open(dir: &Path, partition: ParquetPartition, partition_blocks: u64, timestamp_unit: TimestampUnit, checkpoint_suffix: &str) -> SinkResult<ParquetSink> {
+        std::fs::create_dir_all(dir)?;
+
+        Ok(ParquetSink {
+            dir: dir.to_path_buf(),
+            partition,
+            partition_blocks,
+            timestamp_unit,
+            schema: Arc::new(transfer_schema()),
+            pending: BTreeMap::new(),
+            checkpoint_path: dir.join(format!("{}{}", PARQUET_CHECKPOINT_FILE, checkpoint_suffix)),
+        })
+    }
+
+    fn partition_of(&self, transfer: &Transfer) -> String {
+        match self.partition {
+            ParquetPartition::Day => {
+                let millis = transfer.timestamp * 1000 / self.timestamp_unit.convert(1);
+                let date = DateTime::from_millis(millis as i64).try_to_rfc3339_string().unwrap_or_default();
+
+                format!("day={}", date.get(..10).unwrap_or("unknown"))
+            }
+            ParquetPartition::Blocks => {
+                let first_block = transfer.block_number - transfer.block_number % self.partition_blocks;
+
+                format!("blocks={}-{}", first_block, first_block + self.partition_blocks - 1)
+            }
+        }
+    }
+
+    /// Writes `transfers` to a new file in `partition`, replacing a file of the same block range, e.g. written before
+    /// a restart.
+    fn write_partition(&self, partition: &str, transfers: &[Transfer]) -> SinkResult<()> {
+        let (Some(first), Some(last)) = (transfers.first(), transfers.last()) else {
+            return Ok(());
+        };
+
+        let dir = self.dir.join(partition);
+        std::fs::create_dir_all(&dir)?;
+
+        let path = dir.join(format!("{:020}-{:020}.parquet", first.block_number, last.block_number));
+        let temp_path = path.with_extension("tmp");
+
+        let properties = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+        let mut writer = ArrowWriter::try_new(File::create(&temp_path)?, self.schema.clone(), Some(properties))?;
+        writer.write(&record_batch(self.schema.clone(), transfers)?)?;
+        writer.close()?;
+
+        std::fs::rename(&temp_path, &path)?;
+
+        Ok(())
+    }
+}
+
+fn transfer_schema() -> Schema {
+    let text = |name: &str| Field::new(name, DataType::Utf8, false);
+    let number = |name: &str, nullable: bool| Field::new(name, DataType::UInt64, nullable);
+
+    Schema::new(vec![
+        text("id"),
+        Field::new("schema_version", DataType::UInt8, false),
+        number("chain_id", true),
+        text("contract"),
+        text("event"),
+        text("from"),
+        text("to"),
+        text("value"),
+        text("value_decimal"),
+        number("timestamp", false),
+        number("block_number", false),
+        text("block_hash"),
+        text("transaction_hash"),
+        text("tx_from"),
+        number("log_index", false),
+        text("transfer_kind"),
+        number("gas_used", true),
+        number("effective_gas_price", true),
+        number("sibling_log_count", true),
+    ])
+}
+
+/// Converts `transfers` to the columns of `transfer_schema`, in the same order.
+fn record_batch(schema: SchemaRef, transfers: &[Transfer]) -> SinkResult<RecordBatch> {
+    let text = |value: fn(&Transfer) -> &str| Arc::new(StringArray::from_iter_values(transfers.iter().map(value))) as ArrayRef;
+    let number = |value: fn(&Transfer) -> Option<u64>| Arc::new(transfers.iter().map(value).collect::<UInt64Array>()) as ArrayRef;
+
+    let columns = vec![
+        text(|t| &t.id),
+        Arc::new(UInt8Array::from_iter_values(transfers.iter().map(|t| t.schema_version))) as ArrayRef,
+        number(|t| t.chain_id),
+        text(|t| &t.contract),
+        text(|t| &t.event),
+        text(|t| &t.from),
+        text(|t| &t.to),
+        text(|t| &t.value),
+        text(|t| &t.value_decimal),
+        number(|t| Some(t.timestamp)),
+        number(|t| Some(t.block_number)),
+        text(|t| &t.block_hash),
+        text(|t| &t.transaction_hash),
+        text(|t| &t.tx_from),
+        number(|t| Some(t.log_index)),
+        text(|t| t.transfer_kind.as_str()),
+        number(|t| t.gas_used),
+        number(|t| t.effective_gas_price),
+        number(|t| t.sibling_log_count),
+    ];
+
+    Ok(RecordBatch::try_new(schema, columns)?)
+}
+
+#[async_trait]
+impl TransferSink for ParquetSink {
+    async fn insert_batch(&mut self, transfers: &[Transfer]) -> SinkResult<()> {
+        for transfer in transfers {
+            let partition = self.partition_of(transfer);
+            self.pending.entry(partition).or_default().push(transfer.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Drops the buffered transfers even when writing fails, the writer retries by inserting the whole batch again and
+    /// the files already written are replaced.
+    async fn flush(&mut self) -> SinkResult<()> {
+        let pending = std::mem::take(&mut self.pending);
+
+        block_in_place(|| {
+            pending
+                .iter()
+                .try_for_each(|(partition, transfers)| self.write_partition(partition, transfers))
+        })
+    }
+
+    async fn load_checkpoint(&self) -> SinkResult<Option<u64>> {
+        match std::fs::read_to_string(&self.checkpoint_path) {
+            Ok(block_number) => Ok(Some(block_number.trim().parse()?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn save_checkpoint(&mut self, block_number: u64) -> SinkResult<()> {
+        let temp_path = self.checkpoint_path.with_extension("tmp");
+
+        std::fs::write(&temp_path, block_number.to_string())?;
+        std::fs::rename(&temp_path, &self.checkpoint_path)?;
+
+        Ok(())
+    }
+}
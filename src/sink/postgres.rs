@@ -0,0 +1,310 @@
+use std::error::Error;
+use async_trait::async_trait;
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use sqlx::{Postgres, QueryBuilder};
+use tracing::info;
+use crate::models::{AddressActivity, BlockStats, DailyVolume, Reorg, Transfer};
+use crate::sink::{count_hashes, SinkResult, TransferSink};
+
+const POSTGRES_CHECKPOINT_ID: &str = "transfers";
+
+// query_canceled, raised by statement_timeout, and program_limit_exceeded.
+const TOO_LARGE_ERROR_CODES: [&str; 2] = ["57014", "54000"];
+
+// Postgres caps a statement at 65535 bind parameters, seventeen per transfer row.
+const POSTGRES_INSERT_CHUNK_SIZE: usize = 3800;
+
+pub struct PostgresSink {
+    pool: PgPool,
+    checkpoint_id: String,
+}
+
+impl PostgresSink {
+    pub async fn connect(database_url: &str, checkpoint_suffix: &str) -> SinkResult<PostgresSink> {
+        let pool = PgPoolOptions::new().connect(database_url).await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS transfers (
+                id TEXT NOT NULL,
+                chain_id BIGINT NOT NULL DEFAULT 0,
+                contract TEXT NOT NULL,
+                \"from\" TEXT NOT NULL,
+                \"to\" TEXT NOT NULL,
+                value TEXT NOT NULL,
+                value_decimal TEXT NOT NULL,
+                timestamp BIGINT NOT NULL,
+                block_number BIGINT NOT NULL,
+                block_hash TEXT NOT NULL,
+                tx_hash TEXT NOT NULL,
+                tx_from TEXT NOT NULL,
+                log_index BIGINT NOT NULL,
+                transfer_kind TEXT NOT NULL,
+                gas_used BIGINT,
+                effective_gas_price BIGINT,
+                sibling_log_count BIGINT
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        // Tables created before tx_from, chain_id, transfer_kind, id, gas and sibling logs were recorded.
+        sqlx::query("ALTER TABLE transfers ADD COLUMN IF NOT EXISTS tx_from TEXT NOT NULL DEFAULT ''")
+            .execute(&pool)
+            .await?;
+
+        sqlx::query("ALTER TABLE transfers ADD COLUMN IF NOT EXISTS chain_id BIGINT NOT NULL DEFAULT 0")
+            .execute(&pool)
+            .await?;
+
+        sqlx::query("ALTER TABLE transfers ADD COLUMN IF NOT EXISTS transfer_kind TEXT NOT NULL DEFAULT 'transfer'")
+            .execute(&pool)
+            .await?;
+
+        sqlx::query("ALTER TABLE transfers ADD COLUMN IF NOT EXISTS id TEXT NOT NULL DEFAULT ''")
+            .execute(&pool)
+            .await?;
+
+        sqlx::query("ALTER TABLE transfers ADD COLUMN IF NOT EXISTS gas_used BIGINT, ADD COLUMN IF NOT EXISTS effective_gas_price BIGINT")
+            .execute(&pool)
+            .await?;
+
+        sqlx::query("ALTER TABLE transfers ADD COLUMN IF NOT EXISTS sibling_log_count BIGINT")
+            .execute(&pool)
+            .await?;
+
+        // Tables created before chain_id was recorded are keyed by log position alone, which drops the same position on
+        // another chain.
+        sqlx::query("ALTER TABLE transfers DROP CONSTRAINT IF EXISTS transfers_tx_hash_log_index_key")
+            .execute(&pool)
+            .await?;
+
+        sqlx::query("CREATE UNIQUE INDEX IF NOT EXISTS transfers_chain_id_tx_hash_log_index_idx ON transfers (chain_id, tx_hash, log_index)")
+            .execute(&pool)
+            .await?;
+
+        for column in ["id", "chain_id", "contract", "\"from\"", "\"to\"", "timestamp", "block_number", "tx_hash", "tx_from", "transfer_kind"] {
+            let name = column.trim_matches('"');
+            sqlx::query(&format!("CREATE INDEX IF NOT EXISTS transfers_{}_idx ON transfers ({})", name, column))
+                .execute(&pool)
+                .await?;
+        }
+
+        sqlx::query("CREATE TABLE IF NOT EXISTS block_stats (chain_id BIGINT NOT NULL DEFAULT 0, block_number BIGINT NOT NULL, timestamp BIGINT NOT NULL, transfer_count BIGINT NOT NULL)")
+            .execute(&pool)
+            .await?;
+
+        sqlx::query("ALTER TABLE block_stats ADD COLUMN IF NOT EXISTS chain_id BIGINT NOT NULL DEFAULT 0")
+            .execute(&pool)
+            .await?;
+
+        // Tables created before chain_id was recorded are keyed by block number alone, which rejects the same block of another chain.
+        sqlx::query("ALTER TABLE block_stats DROP CONSTRAINT IF EXISTS block_stats_pkey")
+            .execute(&pool)
+            .await?;
+
+        sqlx::query("CREATE UNIQUE INDEX IF NOT EXISTS block_stats_chain_id_block_number_idx ON block_stats (chain_id, block_number)")
+            .execute(&pool)
+            .await?;
+
+        sqlx::query("CREATE TABLE IF NOT EXISTS address_activity (chain_id BIGINT NOT NULL DEFAULT 0, address TEXT NOT NULL, first_block BIGINT NOT NULL, last_block BIGINT NOT NULL, transfer_count BIGINT NOT NULL, PRIMARY KEY (chain_id, address))")
+            .execute(&pool)
+            .await?;
+
+        sqlx::query("CREATE TABLE IF NOT EXISTS daily_volume (chain_id BIGINT NOT NULL DEFAULT 0, contract TEXT NOT NULL, day BIGINT NOT NULL, volume NUMERIC NOT NULL, transfer_count BIGINT NOT NULL, PRIMARY KEY (chain_id, contract, day))")
+            .execute(&pool)
+            .await?;
+
+        sqlx::query("CREATE TABLE IF NOT EXISTS reorgs (chain_id BIGINT NOT NULL DEFAULT 0, block_number BIGINT NOT NULL, old_hash TEXT NOT NULL, new_hash TEXT NOT NULL, transfers_removed BIGINT NOT NULL, timestamp BIGINT NOT NULL)")
+            .execute(&pool)
+            .await?;
+
+        sqlx::query("CREATE TABLE IF NOT EXISTS checkpoints (id TEXT PRIMARY KEY, block_number BIGINT NOT NULL)")
+            .execute(&pool)
+            .await?;
+
+        Ok(PostgresSink {
+            pool,
+            checkpoint_id: format!("{}{}", POSTGRES_CHECKPOINT_ID, checkpoint_suffix),
+        })
+    }
+}
+
+#[async_trait]
+impl TransferSink for PostgresSink {
+    async fn insert_batch(&mut self, transfers: &[Transfer]) -> SinkResult<()> {
+        let mut inserted = 0;
+        for chunk in transfers.chunks(POSTGRES_INSERT_CHUNK_SIZE) {
+            let mut query: QueryBuilder<Postgres> = QueryBuilder::new(
+                "INSERT INTO transfers (id, chain_id, contract, \"from\", \"to\", value, value_decimal, timestamp, block_number, block_hash, tx_hash, tx_from, log_index, transfer_kind, gas_used, effective_gas_price, sibling_log_count) ",
+            );
+
+            query.push_values(chunk, |mut row, transfer| {
+                row.push_bind(&transfer.id)
+                    .push_bind(transfer.chain_id.unwrap_or_default() as i64)
+                    .push_bind(&transfer.contract)
+                    .push_bind(&transfer.from)
+                    .push_bind(&transfer.to)
+                    .push_bind(&transfer.value)
+                    .push_bind(&transfer.value_decimal)
+                    .push_bind(transfer.timestamp as i64)
+                    .push_bind(transfer.block_number as i64)
+                    .push_bind(&transfer.block_hash)
+                    .push_bind(&transfer.transaction_hash)
+                    .push_bind(&transfer.tx_from)
+                    .push_bind(transfer.log_index as i64)
+                    .push_bind(transfer.transfer_kind.as_str())
+                    .push_bind(transfer.gas_used.map(|gas| gas as i64))
+                    .push_bind(transfer.effective_gas_price.map(|price| price as i64))
+                    .push_bind(transfer.sibling_log_count.map(|count| count as i64));
+            });
+
+            query.push(" ON CONFLICT (chain_id, tx_hash, log_index) DO NOTHING");
+            inserted += query.build().execute(&self.pool).await?.rows_affected();
+        }
+
+        let duplicates = transfers.len() as u64 - inserted;
+        if duplicates > 0 {
+            info!(duplicates, "Skipped transfers that were already stored");
+        }
+
+        Ok(())
+    }
+
+    fn is_too_large(&self, error: &(dyn Error + Send + Sync + 'static)) -> bool {
+        match error.downcast_ref::<sqlx::Error>() {
+            Some(sqlx::Error::Database(e)) => e.code().is_some_and(|code| TOO_LARGE_ERROR_CODES.contains(&code.as_ref())),
+            Some(sqlx::Error::Io(e)) => e.kind() == std::io::ErrorKind::TimedOut,
+            Some(sqlx::Error::PoolTimedOut) => true,
+            _ => false,
+        }
+    }
+
+    async fn insert_block_stats(&mut self, stats: &[BlockStats]) -> SinkResult<()> {
+        for chunk in stats.chunks(POSTGRES_INSERT_CHUNK_SIZE) {
+            let mut query: QueryBuilder<Postgres> = QueryBuilder::new("INSERT INTO block_stats (chain_id, block_number, timestamp, transfer_count) ");
+
+            query.push_values(chunk, |mut row, stats| {
+                row.push_bind(stats.chain_id.unwrap_or_default() as i64)
+                    .push_bind(stats.block_number as i64)
+                    .push_bind(stats.timestamp as i64)
+                    .push_bind(stats.transfer_count as i64);
+            });
+
+            query.push(" ON CONFLICT (chain_id, block_number) DO UPDATE SET timestamp = EXCLUDED.timestamp, transfer_count = EXCLUDED.transfer_count");
+            query.build().execute(&self.pool).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn insert_address_activity(&mut self, activity: &[AddressActivity]) -> SinkResult<()> {
+        for chunk in activity.chunks(POSTGRES_INSERT_CHUNK_SIZE) {
+            let mut query: QueryBuilder<Postgres> = QueryBuilder::new("INSERT INTO address_activity (chain_id, address, first_block, last_block, transfer_count) ");
+
+            query.push_values(chunk, |mut row, activity| {
+                row.push_bind(activity.chain_id.unwrap_or_default() as i64)
+                    .push_bind(&activity.address)
+                    .push_bind(activity.first_block as i64)
+                    .push_bind(activity.last_block as i64)
+                    .push_bind(activity.transfer_count as i64);
+            });
+
+            query.push(
+                " ON CONFLICT (chain_id, address) DO UPDATE SET first_block = LEAST(address_activity.first_block, EXCLUDED.first_block), \
+                last_block = GREATEST(address_activity.last_block, EXCLUDED.last_block), transfer_count = address_activity.transfer_count + EXCLUDED.transfer_count",
+            );
+            query.build().execute(&self.pool).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn insert_daily_volume(&mut self, volume: &[DailyVolume]) -> SinkResult<()> {
+        for chunk in volume.chunks(POSTGRES_INSERT_CHUNK_SIZE) {
+            let mut query: QueryBuilder<Postgres> = QueryBuilder::new("INSERT INTO daily_volume (chain_id, contract, day, volume, transfer_count) ");
+
+            query.push_values(chunk, |mut row, volume| {
+                row.push_bind(volume.chain_id.unwrap_or_default() as i64)
+                    .push_bind(&volume.contract)
+                    .push_bind(volume.day as i64)
+                    .push_bind(&volume.volume)
+                    .push_unseparated("::numeric")
+                    .push_bind(volume.transfer_count as i64);
+            });
+
+            query.push(
+                " ON CONFLICT (chain_id, contract, day) DO UPDATE SET volume = daily_volume.volume + EXCLUDED.volume, \
+                transfer_count = daily_volume.transfer_count + EXCLUDED.transfer_count",
+            );
+            query.build().execute(&self.pool).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn delete_orphaned(&mut self, chain_id: Option<u64>, block_number: u64, block_hash: &str) -> SinkResult<Vec<(String, u64)>> {
+        let orphaned_hashes: Vec<String> = sqlx::query_scalar("DELETE FROM transfers WHERE chain_id = $1 AND block_number = $2 AND block_hash <> $3 RETURNING block_hash")
+            .bind(chain_id.unwrap_or_default() as i64)
+            .bind(block_number as i64)
+            .bind(block_hash)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(count_hashes(orphaned_hashes))
+    }
+
+    async fn insert_reorgs(&mut self, reorgs: &[Reorg]) -> SinkResult<()> {
+        let mut query: QueryBuilder<Postgres> = QueryBuilder::new("INSERT INTO reorgs (chain_id, block_number, old_hash, new_hash, transfers_removed, timestamp) ");
+        query.push_values(reorgs, |mut row, reorg| {
+            row.push_bind(reorg.chain_id.unwrap_or_default() as i64)
+                .push_bind(reorg.block_number as i64)
+                .push_bind(&reorg.old_hash)
+                .push_bind(&reorg.new_hash)
+                .push_bind(reorg.transfers_removed as i64)
+                .push_bind(reorg.timestamp as i64);
+        });
+        query.build().execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn delete_block(&mut self, chain_id: Option<u64>, block_number: u64) -> SinkResult<u64> {
+        let result = sqlx::query("DELETE FROM transfers WHERE chain_id = $1 AND block_number = $2")
+            .bind(chain_id.unwrap_or_default() as i64)
+            .bind(block_number as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn prune_transfers(&mut self, chain_id: Option<u64>, before_block: u64, limit: usize) -> SinkResult<u64> {
+        let result = sqlx::query("DELETE FROM transfers WHERE ctid IN (SELECT ctid FROM transfers WHERE chain_id = $1 AND block_number < $2 LIMIT $3)")
+            .bind(chain_id.unwrap_or_default() as i64)
+            .bind(before_block as i64)
+            .bind(limit as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn load_checkpoint(&self) -> SinkResult<Option<u64>> {
+        let block_number: Option<i64> = sqlx::query_scalar("SELECT block_number FROM checkpoints WHERE id = $1")
+            .bind(&self.checkpoint_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(block_number.map(|block_number| block_number as u64))
+    }
+
+    async fn save_checkpoint(&mut self, block_number: u64) -> SinkResult<()> {
+        sqlx::query("INSERT INTO checkpoints (id, block_number) VALUES ($1, $2) ON CONFLICT (id) DO UPDATE SET block_number = EXCLUDED.block_number")
+            .bind(&self.checkpoint_id)
+            .bind(block_number as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,295 @@
+use std::path::Path;
+use std::sync::Mutex;
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+use tokio::task::block_in_place;
+use tracing::info;
+use crate::models::{AddressActivity, BlockStats, Reorg, Transfer};
+use crate::sink::{count_hashes, SinkResult, TransferSink};
+
+const SQLITE_CHECKPOINT_ID: &str = "transfers";
+
+/// Writes to a local SQLite database file. Queries block the worker thread, which is fine for a
+/// file on the same machine.
+pub struct SqliteSink {
+    // A connection can be sent between threads but not shared, the sink has to be both.
+    connection: Mutex<Connection>,
+    checkpoint_id: String,
+}
+
+const TRANSFERS_TABLE: &str = "CREATE TABLE IF NOT EXISTS transfers (
+    id TEXT NOT NULL,
+    chain_id INTEGER NOT NULL DEFAULT 0,
+    contract TEXT NOT NULL,
+    \"from\" TEXT NOT NULL,
+    \"to\" TEXT NOT NULL,
+    value TEXT NOT NULL,
+    value_decimal TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    block_number INTEGER NOT NULL,
+    block_hash TEXT NOT NULL,
+    tx_hash TEXT NOT NULL,
+    tx_from TEXT NOT NULL,
+    log_index INTEGER NOT NULL,
+    transfer_kind TEXT NOT NULL,
+    gas_used INTEGER,
+    effective_gas_price INTEGER,
+    sibling_log_count INTEGER,
+    UNIQUE (chain_id, tx_hash, log_index)
+)";
+
+const TRANSFER_COLUMNS: &str = "id, chain_id, contract, \"from\", \"to\", value, value_decimal, timestamp, block_number, block_hash, tx_hash, tx_from, log_index, transfer_kind, gas_used, effective_gas_price, sibling_log_count";
+
+impl SqliteSink {
+    pub fn open(path: &Path, checkpoint_suffix: &str) -> SinkResult<SqliteSink> {
+        let connection = Connection::open(path)?;
+
+        connection.execute_batch("PRAGMA journal_mode = WAL")?;
+        connection.execute(TRANSFERS_TABLE, [])?;
+
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS block_stats (
+                chain_id INTEGER NOT NULL DEFAULT 0,
+                block_number INTEGER NOT NULL,
+                timestamp INTEGER NOT NULL,
+                transfer_count INTEGER NOT NULL,
+                UNIQUE (chain_id, block_number)
+            );
+            CREATE TABLE IF NOT EXISTS address_activity (
+                chain_id INTEGER NOT NULL DEFAULT 0,
+                address TEXT NOT NULL,
+                first_block INTEGER NOT NULL,
+                last_block INTEGER NOT NULL,
+                transfer_count INTEGER NOT NULL,
+                PRIMARY KEY (chain_id, address)
+            );
+            CREATE TABLE IF NOT EXISTS reorgs (
+                chain_id INTEGER NOT NULL DEFAULT 0,
+                block_number INTEGER NOT NULL,
+                old_hash TEXT NOT NULL,
+                new_hash TEXT NOT NULL,
+                transfers_removed INTEGER NOT NULL,
+                timestamp INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS checkpoints (id TEXT PRIMARY KEY, block_number INTEGER NOT NULL);",
+        )?;
+
+        // Tables created before ids, gas and sibling logs were recorded, SQLite has no ADD COLUMN IF NOT EXISTS.
+        let columns = [("id", "TEXT NOT NULL DEFAULT ''"), ("gas_used", "INTEGER"), ("effective_gas_price", "INTEGER"), ("sibling_log_count", "INTEGER")];
+        for (column, definition) in columns {
+            let exists: bool = connection.query_row("SELECT COUNT(*) > 0 FROM pragma_table_info('transfers') WHERE name = ?1", [column], |row| row.get(0))?;
+            if !exists {
+                connection.execute(&format!("ALTER TABLE transfers ADD COLUMN {} {}", column, definition), [])?;
+            }
+        }
+
+        // Tables created before chain_id was part of the key drop the same log position on another chain. SQLite can't
+        // drop a table constraint, so the table is rebuilt with the new one.
+        let table: String = connection.query_row("SELECT sql FROM sqlite_master WHERE type = 'table' AND name = 'transfers'", [], |row| row.get(0))?;
+        if table.contains("UNIQUE (tx_hash, log_index)") {
+            connection.execute_batch(&format!(
+                "BEGIN;
+                ALTER TABLE transfers RENAME TO transfers_without_chain;
+                {};
+                INSERT INTO transfers ({columns}) SELECT {columns} FROM transfers_without_chain;
+                DROP TABLE transfers_without_chain;
+                COMMIT;",
+                TRANSFERS_TABLE,
+                columns = TRANSFER_COLUMNS,
+            ))?;
+        }
+
+        for column in ["id", "chain_id", "contract", "\"from\"", "\"to\"", "timestamp", "block_number", "tx_hash", "tx_from", "transfer_kind"] {
+            let name = column.trim_matches('"');
+            connection.execute(&format!("CREATE INDEX IF NOT EXISTS transfers_{}_idx ON transfers ({})", name, column), [])?;
+        }
+
+        Ok(SqliteSink {
+            connection: Mutex::new(connection),
+            checkpoint_id: format!("{}{}", SQLITE_CHECKPOINT_ID, checkpoint_suffix),
+        })
+    }
+
+    fn connection(&mut self) -> &mut Connection {
+        self.connection.get_mut().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[async_trait]
+impl TransferSink for SqliteSink {
+    /// Inserts the batch in a single transaction, SQLite commits are slow but inserts within one are cheap.
+    async fn insert_batch(&mut self, transfers: &[Transfer]) -> SinkResult<()> {
+        block_in_place(|| {
+            let mut inserted = 0;
+            let tx = self.connection().transaction()?;
+            {
+                let mut statement = tx.prepare_cached(
+                    &format!("INSERT OR IGNORE INTO transfers ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)", TRANSFER_COLUMNS),
+                )?;
+
+                for transfer in transfers {
+                    inserted += statement.execute(params![
+                        transfer.id,
+                        transfer.chain_id.unwrap_or_default() as i64,
+                        transfer.contract,
+                        transfer.from,
+                        transfer.to,
+                        transfer.value,
+                        transfer.value_decimal,
+                        transfer.timestamp as i64,
+                        transfer.block_number as i64,
+                        transfer.block_hash,
+                        transfer.transaction_hash,
+                        transfer.tx_from,
+                        transfer.log_index as i64,
+                        transfer.transfer_kind.as_str(),
+                        transfer.gas_used.map(|gas| gas as i64),
+                        transfer.effective_gas_price.map(|price| price as i64),
+                        transfer.sibling_log_count.map(|count| count as i64),
+                    ])?;
+                }
+            }
+            tx.commit()?;
+
+            let duplicates = transfers.len() - inserted;
+            if duplicates > 0 {
+                info!(duplicates, "Skipped transfers that were already stored");
+            }
+
+            Ok(())
+        })
+    }
+
+    async fn insert_block_stats(&mut self, stats: &[BlockStats]) -> SinkResult<()> {
+        block_in_place(|| {
+            let tx = self.connection().transaction()?;
+            {
+                let mut statement = tx.prepare_cached(
+                    "INSERT INTO block_stats (chain_id, block_number, timestamp, transfer_count) VALUES (?1, ?2, ?3, ?4)
+                    ON CONFLICT (chain_id, block_number) DO UPDATE SET timestamp = excluded.timestamp, transfer_count = excluded.transfer_count",
+                )?;
+
+                for stats in stats {
+                    statement.execute(params![
+                        stats.chain_id.unwrap_or_default() as i64,
+                        stats.block_number as i64,
+                        stats.timestamp as i64,
+                        stats.transfer_count as i64,
+                    ])?;
+                }
+            }
+            tx.commit()?;
+
+            Ok(())
+        })
+    }
+
+    async fn insert_address_activity(&mut self, activity: &[AddressActivity]) -> SinkResult<()> {
+        block_in_place(|| {
+            let tx = self.connection().transaction()?;
+            {
+                let mut statement = tx.prepare_cached(
+                    "INSERT INTO address_activity (chain_id, address, first_block, last_block, transfer_count) VALUES (?1, ?2, ?3, ?4, ?5)
+                    ON CONFLICT (chain_id, address) DO UPDATE SET first_block = MIN(first_block, excluded.first_block),
+                    last_block = MAX(last_block, excluded.last_block), transfer_count = transfer_count + excluded.transfer_count",
+                )?;
+
+                for activity in activity {
+                    statement.execute(params![
+                        activity.chain_id.unwrap_or_default() as i64,
+                        activity.address,
+                        activity.first_block as i64,
+                        activity.last_block as i64,
+                        activity.transfer_count as i64,
+                    ])?;
+                }
+            }
+            tx.commit()?;
+
+            Ok(())
+        })
+    }
+
+    async fn delete_orphaned(&mut self, chain_id: Option<u64>, block_number: u64, block_hash: &str) -> SinkResult<Vec<(String, u64)>> {
+        let orphaned_hashes = block_in_place(|| {
+            self.connection()
+                .prepare("DELETE FROM transfers WHERE chain_id = ?1 AND block_number = ?2 AND block_hash <> ?3 RETURNING block_hash")?
+                .query_map(params![chain_id.unwrap_or_default() as i64, block_number as i64, block_hash], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<String>>>()
+        })?;
+
+        Ok(count_hashes(orphaned_hashes))
+    }
+
+    async fn insert_reorgs(&mut self, reorgs: &[Reorg]) -> SinkResult<()> {
+        block_in_place(|| {
+            let tx = self.connection().transaction()?;
+            {
+                let mut statement = tx.prepare_cached(
+                    "INSERT INTO reorgs (chain_id, block_number, old_hash, new_hash, transfers_removed, timestamp) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                )?;
+
+                for reorg in reorgs {
+                    statement.execute(params![
+                        reorg.chain_id.unwrap_or_default() as i64,
+                        reorg.block_number as i64,
+                        reorg.old_hash,
+                        reorg.new_hash,
+                        reorg.transfers_removed as i64,
+                        reorg.timestamp as i64,
+                    ])?;
+                }
+            }
+            tx.commit()?;
+
+            Ok(())
+        })
+    }
+
+    async fn delete_block(&mut self, chain_id: Option<u64>, block_number: u64) -> SinkResult<u64> {
+        let deleted = block_in_place(|| {
+            self.connection().execute(
+                "DELETE FROM transfers WHERE chain_id = ?1 AND block_number = ?2",
+                params![chain_id.unwrap_or_default() as i64, block_number as i64],
+            )
+        })?;
+
+        Ok(deleted as u64)
+    }
+
+    async fn prune_transfers(&mut self, chain_id: Option<u64>, before_block: u64, limit: usize) -> SinkResult<u64> {
+        let deleted = block_in_place(|| {
+            self.connection().execute(
+                "DELETE FROM transfers WHERE rowid IN (SELECT rowid FROM transfers WHERE chain_id = ?1 AND block_number < ?2 LIMIT ?3)",
+                params![chain_id.unwrap_or_default() as i64, before_block as i64, limit as i64],
+            )
+        })?;
+
+        Ok(deleted as u64)
+    }
+
+    async fn load_checkpoint(&self) -> SinkResult<Option<u64>> {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+
+        let block_number: Option<i64> = block_in_place(|| {
+            connection
+                .query_row("SELECT block_number FROM checkpoints WHERE id = ?1", [&self.checkpoint_id], |row| row.get(0))
+                .optional()
+        })?;
+
+        Ok(block_number.map(|block_number| block_number as u64))
+    }
+
+    async fn save_checkpoint(&mut self, block_number: u64) -> SinkResult<()> {
+        let checkpoint_id = self.checkpoint_id.clone();
+
+        block_in_place(|| {
+            self.connection().execute(
+                "INSERT INTO checkpoints (id, block_number) VALUES (?1, ?2) ON CONFLICT (id) DO UPDATE SET block_number = excluded.block_number",
+                params![checkpoint_id, block_number as i64],
+            )
+        })?;
+
+        Ok(())
+    }
+}
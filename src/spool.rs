@@ -0,0 +1,115 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use crate::models::{AddressActivity, Approval, BlockStats, DailyVolume, GenericEvent, Transfer};
+use crate::parser::Records;
+use crate::sink::TransferSink;
+use crate::writer::Batch;
+
+const SPOOL_EXTENSION: &str = ".ndjson.gz";
+
+/// A line of a spool file.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SpoolRecord {
+    Transfer(Transfer),
+    Approval(Approval),
+    Event(GenericEvent),
+    BlockStats(BlockStats),
+    AddressActivity(AddressActivity),
+    DailyVolume(DailyVolume),
+}
+
+/// Writes the records of a batch the sink failed to store to a gzip compressed NDJSON file in `dir`, named after its
+/// last block so files replay in block order. The file only appears once it's complete.
+pub fn write(dir: &Path, batch: &Batch, address_activity: &[AddressActivity]) -> io::Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_millis()).unwrap_or_default();
+    let path = dir.join(format!("{:020}-{}{}", batch.last_block, millis, SPOOL_EXTENSION));
+    let temp_path = path.with_extension("tmp");
+
+    let Records { transfers, approvals, events } = &batch.records;
+    let records = transfers.iter().cloned().map(SpoolRecord::Transfer)
+        .chain(approvals.iter().cloned().map(SpoolRecord::Approval))
+        .chain(events.iter().cloned().map(SpoolRecord::Event))
+        .chain(batch.block_stats.iter().cloned().map(SpoolRecord::BlockStats))
+        .chain(address_activity.iter().cloned().map(SpoolRecord::AddressActivity))
+        .chain(batch.daily_volume.iter().cloned().map(SpoolRecord::DailyVolume));
+
+    let mut writer = GzEncoder::new(BufWriter::new(File::create(&temp_path)?), Compression::default());
+    for record in records {
+        serde_json::to_writer(&mut writer, &record)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.finish()?.into_inner()?.sync_all()?;
+
+    std::fs::rename(&temp_path, &path)?;
+
+    Ok(path)
+}
+
+/// Replays the spool files in `dir` into `sink` in block order, writing transfers, approvals and events in chunks of
+/// `batch_size` and deleting each file once it's stored. Returns the number of files and transfers replayed. Stored
+/// records are skipped as duplicates, but the aggregates of a file that failed midway are added again on the next replay.
+pub async fn replay(sink: &mut dyn TransferSink, dir: &Path, batch_size: usize) -> Result<(usize, u64), Box<dyn Error + Send + Sync>> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<_>>()?;
+    paths.retain(|path| path.to_string_lossy().ends_with(SPOOL_EXTENSION));
+    paths.sort();
+
+    let mut transfers_replayed = 0;
+    for path in &paths {
+        let (mut records, mut block_stats, mut address_activity, mut daily_volume) = (Records::default(), vec![], vec![], vec![]);
+
+        let reader = BufReader::new(GzDecoder::new(File::open(path)?));
+        for (number, line) in reader.lines().enumerate() {
+            match serde_json::from_str(&line?).map_err(|e| format!("{} line {}: {}", path.display(), number + 1, e))? {
+                SpoolRecord::Transfer(transfer) => records.transfers.push(transfer),
+                SpoolRecord::Approval(approval) => records.approvals.push(approval),
+                SpoolRecord::Event(event) => records.events.push(event),
+                SpoolRecord::BlockStats(stats) => block_stats.push(stats),
+                SpoolRecord::AddressActivity(activity) => address_activity.push(activity),
+                SpoolRecord::DailyVolume(volume) => daily_volume.push(volume),
+            }
+        }
+
+        for chunk in records.transfers.chunks(batch_size) {
+            sink.insert_batch(chunk).await?;
+        }
+
+        for chunk in records.approvals.chunks(batch_size) {
+            sink.insert_approvals(chunk).await?;
+        }
+
+        for chunk in records.events.chunks(batch_size) {
+            sink.insert_events(chunk).await?;
+        }
+
+        if !block_stats.is_empty() {
+            sink.insert_block_stats(&block_stats).await?;
+        }
+
+        if !address_activity.is_empty() {
+            sink.insert_address_activity(&address_activity).await?;
+        }
+
+        if !daily_volume.is_empty() {
+            sink.insert_daily_volume(&daily_volume).await?;
+        }
+
+        sink.flush().await?;
+        std::fs::remove_file(path)?;
+
+        transfers_replayed += records.transfers.len() as u64;
+    }
+
+    Ok((paths.len(), transfers_replayed))
+}
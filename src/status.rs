@@ -0,0 +1,40 @@
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+
+/// Indexer progress written to `--status-file` after every persisted batch, for monitoring without an HTTP server.
+#[derive(Serialize)]
+pub struct Status {
+    /// Last block whose records are persisted.
+    pub current_block: u64,
+    /// `None` while the chain head was never queried, e.g. with `--end-block`.
+    pub chain_head: Option<u64>,
+    /// Transfers persisted since startup.
+    pub total_transfers: u64,
+    /// Batches waiting for the writer.
+    pub pending: usize,
+    /// Unix timestamp of the update in seconds.
+    pub last_update: u64,
+}
+
+impl Status {
+    pub fn now(current_block: u64, chain_head: Option<u64>, total_transfers: u64, pending: usize) -> Status {
+        Status {
+            current_block,
+            chain_head,
+            total_transfers,
+            pending,
+            last_update: SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or_default(),
+        }
+    }
+
+    /// Replaces `path` through a temporary file next to it, so readers never see a partially written status.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+
+        std::fs::write(&temp_path, serde_json::to_vec(self)?)?;
+        std::fs::rename(&temp_path, path)
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use tracing::{info, warn};
+use web3::ethabi::{decode, ParamType, Token};
+use web3::types::{BlockNumber, Bytes, CallRequest, H160, U256};
+use crate::contracts::{to_map, Contract, ContractEntry};
+use crate::contracts::ContractType::ERC20;
+use crate::rpc::Rpc;
+use crate::RPC_RECEIPT_RETRIES;
+
+const NAME_SELECTOR: [u8; 4] = [0x06, 0xfd, 0xde, 0x03];
+const SYMBOL_SELECTOR: [u8; 4] = [0x95, 0xd8, 0x9b, 0x41];
+const DECIMALS_SELECTOR: [u8; 4] = [0x31, 0x3c, 0xe5, 0x67];
+
+/// `keccak256("eip1967.proxy.implementation") - 1`, the storage slot EIP-1967 proxies keep their implementation in.
+const EIP1967_IMPLEMENTATION_SLOT: &str = "360894a13ba1a3210667c828492db98dca3e2076cc3735a920a3ca505d382bbc";
+
+/// Metadata reported by a token contract, `None` where the call reverted or returned garbage.
+pub struct TokenMetadata {
+    pub name: Option<String>,
+    pub symbol: Option<String>,
+    pub decimals: Option<usize>,
+    /// Set when `decimals()` failed on an EIP-1967 proxy and the missing metadata was read from its implementation.
+    pub implementation: Option<H160>,
+}
+
+/// Calls `name()`, `symbol()` and `decimals()`. Proxies that don't forward a failed `decimals()` call are asked
+/// through the implementation in their EIP-1967 slot instead.
+pub async fn fetch_metadata(rpc: &Rpc, address: H160) -> TokenMetadata {
+    let mut metadata = TokenMetadata {
+        name: call(rpc, address, NAME_SELECTOR).await.and_then(|bytes| decode_string(&bytes)),
+        symbol: call(rpc, address, SYMBOL_SELECTOR).await.and_then(|bytes| decode_string(&bytes)),
+        decimals: call(rpc, address, DECIMALS_SELECTOR).await.and_then(|bytes| decode_uint(&bytes)),
+        implementation: None,
+    };
+
+    if metadata.decimals.is_some() {
+        return metadata;
+    }
+
+    let Some(implementation) = implementation(rpc, address).await else {
+        return metadata;
+    };
+
+    metadata.decimals = call(rpc, implementation, DECIMALS_SELECTOR).await.and_then(|bytes| decode_uint(&bytes));
+    if metadata.decimals.is_none() {
+        return metadata;
+    }
+
+    info!(address = %format!("{:#x}", address), implementation = %format!("{:#x}", implementation), "Read token metadata from the proxy implementation");
+
+    if metadata.name.is_none() {
+        metadata.name = call(rpc, implementation, NAME_SELECTOR).await.and_then(|bytes| decode_string(&bytes));
+    }
+    if metadata.symbol.is_none() {
+        metadata.symbol = call(rpc, implementation, SYMBOL_SELECTOR).await.and_then(|bytes| decode_string(&bytes));
+    }
+    metadata.implementation = Some(implementation);
+
+    metadata
+}
+
+/// The implementation address in the EIP-1967 slot of `address`, `None` if the slot is empty, i.e. it is no such proxy.
+async fn implementation(rpc: &Rpc, address: H160) -> Option<H160> {
+    let slot = U256::from_str_radix(EIP1967_IMPLEMENTATION_SLOT, 16).expect("Invalid EIP-1967 slot");
+
+    let value = rpc.try_call(RPC_RECEIPT_RETRIES, |eth| eth.storage(address, slot, None)).await.ok()?;
+    let implementation = H160::from_slice(&value.as_bytes()[12..]);
+
+    (!implementation.is_zero()).then_some(implementation)
+}
+
+/// Resolves a watchlist, fetching the metadata of entries that don't specify a name or decimals.
+pub async fn resolve_contracts(rpc: &Rpc, entries: Vec<ContractEntry>) -> HashMap<String, Contract> {
+    let mut contracts = vec![];
+    for entry in entries {
+        contracts.push(fetch_contract(rpc, entry).await);
+    }
+
+    to_map(contracts)
+}
+
+/// Populates a contract from the chain, calling `name()`, `symbol()` and `decimals()` only when the entry
+/// leaves the name or decimals out. Non-standard tokens fall back to their symbol or address as name and 0 decimals.
+pub async fn fetch_contract(rpc: &Rpc, entry: ContractEntry) -> Contract {
+    let ContractEntry { mut name, mut symbol, mut decimals, erc, address, deploy_block, transfer_abi, transfer_filter, enabled } = entry;
+    let mut implementation = None;
+
+    if name.is_none() || decimals.is_none() {
+        let metadata = fetch_metadata(rpc, address.parse().expect("Watchlist address was validated")).await;
+
+        name = name.or(metadata.name);
+        symbol = symbol.or(metadata.symbol);
+        decimals = decimals.or(metadata.decimals);
+        implementation = metadata.implementation.map(|implementation| format!("{:#x}", implementation));
+    }
+
+    let decimals = decimals.unwrap_or_else(|| {
+        if erc == ERC20 {
+            warn!(address = %address, "Token does not report decimals, defaulting to 0");
+        }
+        0
+    });
+
+    let name = name.or_else(|| symbol.clone()).unwrap_or_else(|| {
+        warn!(address = %address, "Token does not report a name or symbol, using its address");
+        address.clone()
+    });
+
+    Contract { name, symbol, decimals, erc, address, deploy_block, implementation, transfer_abi, transfer_filter, enabled }
+}
+
+/// Finds the block a contract was deployed in by binary searching for the first block where it has code.
+/// Returns `None` if it has no code at `head` or the node has no state for older blocks, which needs an archive node.
+pub async fn find_deploy_block(rpc: &Rpc, address: H160, head: u64) -> Option<u64> {
+    let has_code = |number: u64| async move {
+        rpc.try_call(RPC_RECEIPT_RETRIES, |eth| eth.code(address, Some(BlockNumber::Number(number.into()))))
+            .await
+            .map(|code| !code.0.is_empty())
+    };
+
+    let (mut low, mut high) = (0, head);
+    let result = async {
+        if !has_code(head).await? {
+            return Ok(None);
+        }
+
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match has_code(mid).await? {
+                true => high = mid,
+                false => low = mid + 1,
+            }
+        }
+
+        Ok(Some(low))
+    }.await;
+
+    result.unwrap_or_else(|e: web3::Error| {
+        warn!(address = %format!("{:#x}", address), error = %e, "Failed to search the deployment block");
+        None
+    })
+}
+
+/// The earliest deployment block among `contracts`, taken from the watchlist or searched on chain.
+/// Contracts whose deployment block can't be determined are left out.
+pub async fn earliest_deploy_block(rpc: &Rpc, contracts: &HashMap<String, Contract>) -> Option<u64> {
+    let head = rpc.call(|eth| eth.block_number()).await.as_u64();
+
+    let mut earliest: Option<u64> = None;
+    for contract in contracts.values().filter(|contract| contract.enabled) {
+        let deploy_block = match contract.deploy_block {
+            Some(deploy_block) => Some(deploy_block),
+            None => find_deploy_block(rpc, contract.address.parse().expect("Contract address was validated"), head).await,
+        };
+
+        match deploy_block {
+            Some(deploy_block) => {
+                info!(address = %contract.address, deploy_block, "Found deployment block");
+                earliest = Some(earliest.map_or(deploy_block, |earliest| earliest.min(deploy_block)));
+            }
+            None => warn!(address = %contract.address, "Unknown deployment block"),
+        }
+    }
+
+    earliest
+}
+
+async fn call(rpc: &Rpc, address: H160, selector: [u8; 4]) -> Option<Bytes> {
+    let request = CallRequest {
+        to: Some(address),
+        data: Some(selector.to_vec().into()),
+        ..Default::default()
+    };
+
+    // A revert is a definitive answer for a view call, so don't retry it.
+    rpc.try_call(0, |eth| eth.call(request.clone(), None)).await.ok()
+}
+
+/// Decodes a string return value, falling back to the bytes32 used by older tokens like MKR.
+fn decode_string(bytes: &Bytes) -> Option<String> {
+    if let Some(Token::String(value)) = decode(&[ParamType::String], &bytes.0).ok().and_then(|mut tokens| tokens.pop()) {
+        return Some(value);
+    }
+
+    if bytes.0.len() != 32 {
+        return None;
+    }
+
+    let value = String::from_utf8(bytes.0.iter().copied().take_while(|b| *b != 0).collect()).ok()?;
+    (!value.is_empty()).then_some(value)
+}
+
+fn decode_uint(bytes: &Bytes) -> Option<usize> {
+    match decode(&[ParamType::Uint(8)], &bytes.0).ok()?.pop()? {
+        Token::Uint(value) if value <= u8::MAX.into() => Some(value.as_usize()),
+        _ => None,
+    }
+}
@@ -0,0 +1,220 @@
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::Receiver;
+use tracing::{error, info, warn};
+use crate::health::HEALTH;
+use crate::metrics::METRICS;
+use crate::models::{address_activity, BlockStats, DailyVolume, Reorg};
+use crate::parser::Records;
+use crate::sink::{SinkResult, TransferSink};
+use crate::spool;
+use crate::status::Status;
+use crate::{INSERT_BACKOFF, INSERT_RETRIES};
+
+/// Records of consecutive blocks ending with `last_block`, handed from the indexer loop to the writer.
+pub struct Batch {
+    pub records: Records,
+    pub block_stats: Vec<BlockStats>,
+    /// Only filled with `--daily-volume`.
+    pub daily_volume: Vec<DailyVolume>,
+    /// Number and hash of every block in the batch, only filled with `--reorg-check`.
+    pub blocks: Vec<(u64, String)>,
+    pub last_block: u64,
+}
+
+pub struct Writer {
+    sink: Box<dyn TransferSink>,
+    chain_id: Option<u64>,
+    address_activity: bool,
+    /// Records per insert call, halved whenever the sink reports a write as too large and grown back by a quarter
+    /// after every stored batch, up to `max_chunk_size`.
+    chunk_size: usize,
+    max_chunk_size: usize,
+    status_file: Option<PathBuf>,
+    spool_dir: Option<PathBuf>,
+    total_transfers: u64,
+}
+
+impl Writer {
+    pub fn new(sink: Box<dyn TransferSink>, chain_id: Option<u64>, address_activity: bool, max_chunk_size: usize) -> Writer {
+        Writer {
+            sink,
+            chain_id,
+            address_activity,
+            chunk_size: max_chunk_size,
+            max_chunk_size,
+            status_file: None,
+            spool_dir: None,
+            total_transfers: 0,
+        }
+    }
+
+    /// Writes a `Status` to `status_file` after every persisted batch.
+    pub fn with_status_file(mut self, status_file: Option<PathBuf>) -> Writer {
+        self.status_file = status_file;
+        self
+    }
+
+    /// Writes batches that still fail after all retries to `spool_dir` instead of exiting, to be replayed with
+    /// `import-spool` once the sink recovers.
+    pub fn with_spool_dir(mut self, spool_dir: Option<PathBuf>) -> Writer {
+        self.spool_dir = spool_dir;
+        self
+    }
+
+    /// Writes batches in order until the sending side is dropped, saving a checkpoint after each. The channel is
+    /// bounded, so the indexer loop stops fetching while the sink is behind.
+    pub async fn run(mut self, mut batches: Receiver<Batch>) {
+        while let Some(batch) = batches.recv().await {
+            let last_block = batch.last_block;
+            self.write(batch).await;
+
+            if let Some(path) = &self.status_file {
+                let chain_head = Some(METRICS.chain_head_block.get() as u64).filter(|head| *head > 0);
+
+                if let Err(e) = Status::now(last_block, chain_head, self.total_transfers, batches.len()).write(path) {
+                    warn!(path = %path.display(), error = %e, "Failed to write the status file");
+                }
+            }
+        }
+    }
+
+    async fn write(&mut self, batch: Batch) {
+        let mut reorgs = vec![];
+        for (number, hash) in &batch.blocks {
+            match self.sink.delete_orphaned(self.chain_id, *number, hash).await {
+                Ok(orphaned) => {
+                    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or_default();
+
+                    for (old_hash, deleted) in orphaned {
+                        warn!(block = number, old_hash = %old_hash, hash = %hash, deleted, "Reorg detected, deleted orphaned transfers");
+                        reorgs.push(Reorg {
+                            chain_id: self.chain_id,
+                            block_number: *number,
+                            old_hash,
+                            new_hash: hash.clone(),
+                            transfers_removed: deleted,
+                            timestamp,
+                        });
+                    }
+                }
+                Err(e) => error!(block = number, error = %e, "Failed to check block for reorgs"),
+            }
+        }
+
+        if !reorgs.is_empty() {
+            if let Err(e) = self.sink.insert_reorgs(&reorgs).await {
+                error!(error = %e, "Failed to record reorgs");
+            }
+        }
+
+        // Only advance the checkpoint once the batch is persisted so a restart never skips transfers.
+        let insert_timer = METRICS.batch_insert_duration.start_timer();
+        let mut attempt = 0;
+        let result = loop {
+            match self.insert(&batch).await {
+                // Doesn't count as a retry, halving ends at single records.
+                Err(e) if self.chunk_size > 1 && self.sink.is_too_large(e.as_ref()) => {
+                    self.chunk_size /= 2;
+
+                    warn!(error = %e, chunk_size = self.chunk_size, "Batch is too large to insert, retrying in smaller chunks");
+                }
+                Err(e) if attempt < INSERT_RETRIES => {
+                    let delay = INSERT_BACKOFF * 2u32.pow(attempt);
+                    attempt += 1;
+
+                    warn!(error = %e, attempt, delay_secs = delay.as_secs(), "Failed to insert batch, retrying");
+                    tokio::time::sleep(delay).await;
+                }
+                result => break result,
+            }
+        };
+
+        // Continuing would move past blocks that were never stored, so stop and let a restart pick them up from the
+        // checkpoint, unless the batch can be spooled to disk. Aggregates are only updated after the insert, so the spool
+        // holds them in full.
+        if let Err(e) = result {
+            let Some(dir) = &self.spool_dir else {
+                error!(error = %e, retries = INSERT_RETRIES, last_block = batch.last_block, "Failed to insert batch, exiting");
+                std::process::exit(1);
+            };
+
+            let address_activity = match self.address_activity {
+                true => address_activity(self.chain_id, &batch.records.transfers),
+                false => vec![],
+            };
+
+            match spool::write(dir, &batch, &address_activity) {
+                Ok(path) => warn!(error = %e, retries = INSERT_RETRIES, last_block = batch.last_block, path = %path.display(), "Failed to insert batch, spooled it to disk"),
+                Err(spool_error) => {
+                    error!(error = %e, spool_error = %spool_error, last_block = batch.last_block, "Failed to insert and spool batch, exiting");
+                    std::process::exit(1);
+                }
+            }
+
+            if let Err(e) = self.sink.save_checkpoint(batch.last_block).await {
+                error!(block = batch.last_block, error = %e, "Failed to save checkpoint");
+            }
+
+            return;
+        }
+
+        self.chunk_size = (self.chunk_size + self.chunk_size / 4 + 1).min(self.max_chunk_size);
+
+        if let Err(e) = self.update_aggregates(&batch).await {
+            error!(error = %e, last_block = batch.last_block, "Failed to update the address activity and daily volume, they may miss the batch");
+        }
+
+        let Records { transfers, approvals, events } = &batch.records;
+
+        let insert_duration = insert_timer.stop_and_record();
+        info!(transfers = transfers.len(), approvals = approvals.len(), events = events.len(), duration_secs = insert_duration, "Inserted batch");
+
+        METRICS.transfers_indexed.inc_by(transfers.len() as u64);
+        self.total_transfers += transfers.len() as u64;
+        HEALTH.batch_persisted.store(true, Ordering::SeqCst);
+
+        if let Err(e) = self.sink.save_checkpoint(batch.last_block).await {
+            error!(block = batch.last_block, error = %e, "Failed to save checkpoint");
+        }
+    }
+
+    async fn insert(&mut self, batch: &Batch) -> SinkResult<()> {
+        let Records { transfers, approvals, events } = &batch.records;
+
+        for chunk in transfers.chunks(self.chunk_size) {
+            self.sink.insert_batch(chunk).await?;
+        }
+
+        for chunk in approvals.chunks(self.chunk_size) {
+            self.sink.insert_approvals(chunk).await?;
+        }
+
+        for chunk in events.chunks(self.chunk_size) {
+            self.sink.insert_events(chunk).await?;
+        }
+
+        if !batch.block_stats.is_empty() {
+            self.sink.insert_block_stats(&batch.block_stats).await?;
+        }
+
+        self.sink.flush().await
+    }
+
+    /// Adds the stored batch to the address activity and daily volume. Unlike `insert` this isn't retried, the upserts
+    /// add to counters and may have been partly applied when one fails, so a retry would count transfers twice.
+    async fn update_aggregates(&mut self, batch: &Batch) -> SinkResult<()> {
+        let transfers = &batch.records.transfers;
+
+        if self.address_activity && !transfers.is_empty() {
+            self.sink.insert_address_activity(&address_activity(self.chain_id, transfers)).await?;
+        }
+
+        if !batch.daily_volume.is_empty() {
+            self.sink.insert_daily_volume(&batch.daily_volume).await?;
+        }
+
+        Ok(())
+    }
+}